@@ -0,0 +1,142 @@
+use crate::config::Config;
+
+// Sends events of a host or path prefix to their own Moesif application
+#[derive(Clone, Debug)]
+pub enum ApplicationIdRoute {
+    Host(String, String),
+    PathPrefix(String, String),
+}
+
+impl ApplicationIdRoute {
+    fn parse(route: &str) -> Option<ApplicationIdRoute> {
+        let (matcher, application_id) = route.split_once('=')?;
+        let (kind, value) = matcher.split_once(':')?;
+        let (value, application_id) = (value.trim(), application_id.trim());
+        if value.is_empty() || application_id.is_empty() {
+            return None;
+        }
+
+        match kind.trim() {
+            "host" => Some(ApplicationIdRoute::Host(
+                value.to_lowercase(),
+                application_id.to_string(),
+            )),
+            "path" => Some(ApplicationIdRoute::PathPrefix(
+                value.to_string(),
+                application_id.to_string(),
+            )),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, authority: Option<&str>, path: &str) -> Option<&str> {
+        match self {
+            ApplicationIdRoute::Host(host, application_id) => {
+                let authority = authority?.to_lowercase();
+                // Ignore the port, and allow "*.example.com" to match any subdomain
+                let request_host = host_of(&authority);
+                let matched = match host.strip_prefix("*.") {
+                    Some(domain) => request_host.ends_with(&format!(".{}", domain)),
+                    None => request_host == host,
+                };
+                matched.then_some(application_id.as_str())
+            }
+            ApplicationIdRoute::PathPrefix(prefix, application_id) => {
+                // Whole segments only, "/billing" doesn't match "/billing-public"
+                let matched = match path.strip_prefix(prefix.as_str()) {
+                    Some(rest) => {
+                        rest.is_empty()
+                            || prefix.ends_with('/')
+                            || rest.starts_with('/')
+                            || rest.starts_with('?')
+                    }
+                    None => false,
+                };
+                matched.then_some(application_id.as_str())
+            }
+        }
+    }
+}
+
+// The host of an authority without its port, IPv6 addresses keep their brackets, "[::1]:8080"
+// gives "[::1]"
+fn host_of(authority: &str) -> &str {
+    if authority.starts_with('[') {
+        return match authority.find(']') {
+            Some(end) => &authority[..=end],
+            None => authority,
+        };
+    }
+    authority.split(':').next().unwrap_or_default()
+}
+
+// Parse a comma separated list such as "host:api.example.com=<app id>,path:/billing=<app id>"
+pub fn parse_application_id_routes(value: &str) -> Vec<ApplicationIdRoute> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|route| !route.is_empty())
+        .filter_map(|route| {
+            let parsed = ApplicationIdRoute::parse(route);
+            if parsed.is_none() {
+                log::error!("Ignoring invalid application id route {:?}", route);
+            }
+            parsed
+        })
+        .collect()
+}
+
+// The route metadata wins, then the first matching host or path route, then the global id
+pub fn resolve_application_id(
+    config: &Config,
+    route_application_id: Option<&str>,
+    authority: Option<&str>,
+    path: &str,
+) -> String {
+    route_application_id
+        .or_else(|| {
+            config
+                .application_id_routes
+                .iter()
+                .find_map(|route| route.matches(authority, path))
+        })
+        .unwrap_or(&config.env.moesif_application_id)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EnvConfig;
+
+    #[test]
+    fn test_resolve_application_id() {
+        // arrange
+        let cfg = Config::new(EnvConfig {
+            moesif_application_id: "default-app".to_string(),
+            application_id_routes: Some(
+                "host:*.payments.example.com=payments-app, path:/v1/search=search-app, \
+                host:[::1]=local-app"
+                    .to_string(),
+            ),
+            ..Default::default()
+        });
+
+        // act
+        let by_host = resolve_application_id(&cfg, None, Some("eu.payments.example.com:443"), "/");
+        let by_path = resolve_application_id(&cfg, None, Some("api.example.com"), "/v1/search?q=1");
+        let by_route = resolve_application_id(&cfg, Some("route-app"), None, "/v1/search");
+        let fallback = resolve_application_id(&cfg, None, Some("api.example.com"), "/v1/users");
+        let other_segment =
+            resolve_application_id(&cfg, None, Some("api.example.com"), "/v1/search-public");
+        let ipv6 = resolve_application_id(&cfg, None, Some("[::1]:8080"), "/");
+
+        // assert
+        assert_eq!(by_host, "payments-app");
+        assert_eq!(by_path, "search-app");
+        assert_eq!(by_route, "route-app");
+        assert_eq!(fallback, "default-app");
+        assert_eq!(other_segment, "default-app");
+        assert_eq!(ipv6, "local-app");
+    }
+}
@@ -0,0 +1,402 @@
+use envoy_ext_proc_proto::envoy::extensions::filters::http::ext_proc::v3::{
+    processing_mode::{BodySendMode, HeaderSendMode},
+    ProcessingMode,
+};
+use prost_reflect::MethodDescriptor;
+use std::borrow::Cow;
+
+use crate::body_format::{body_to_json, MultipartSummary};
+use crate::compression::{decompress, is_identity, DecompressError};
+use crate::config::Config;
+use crate::event::Event;
+use crate::grpc_payload::{decode_messages, is_grpc_content_type, method_for};
+use crate::sse::{SseCapture, SseParser};
+
+// Body chunks of one direction of an exchange, kept up to the configured size limit
+#[derive(Default, Debug)]
+pub struct BodyBuffer {
+    pub data: Vec<u8>,
+    // Set when the body outgrew the limit, it is then left out of the event altogether
+    pub omitted: bool,
+}
+
+impl BodyBuffer {
+    pub fn append(&mut self, chunk: &[u8], max_size: usize) {
+        if self.omitted {
+            return;
+        }
+
+        if self.data.len() + chunk.len() > max_size {
+            log::debug!(
+                "Body exceeds {} bytes, dropping it from the event",
+                max_size
+            );
+            self.omitted = true;
+            self.data = Vec::new();
+            return;
+        }
+
+        self.data.extend_from_slice(chunk);
+    }
+}
+
+// Which bodies of an exchange are captured, decided from the request and response headers
+#[derive(Default, Debug)]
+pub struct BodyCapture {
+    pub request: bool,
+    pub response: bool,
+    request_buffer: BodyBuffer,
+    response_buffer: BodyBuffer,
+    // Set for uncompressed multipart uploads, summarized as they stream in instead of buffered
+    request_parts: Option<MultipartSummary>,
+    // Set when the response is a text/event-stream, its chunks are parsed into events
+    response_events: Option<SseCapture>,
+    // Set for gRPC calls whose method is described in the descriptor set
+    grpc_method: Option<MethodDescriptor>,
+}
+
+impl BodyCapture {
+    // Decide on the request headers: bodies are only worth streaming when body logging is on,
+    // the route matches the capture rules and, for the request, the content is textual or a
+    // gRPC message that can be decoded
+    pub fn for_request(config: &Config, event: &Event, request_has_body: bool) -> Self {
+        let route_matches = config.env.log_body
+            && (config.body_capture_paths.is_empty()
+                || config
+                    .body_capture_paths
+                    .iter()
+                    .any(|regex| regex.is_match(&event.request.uri)));
+
+        let content_type = event.request.headers.get("content-type");
+        let grpc_method = content_type
+            .filter(|ct| route_matches && is_grpc_content_type(ct))
+            .and_then(|_| method_for(config, &event.request.uri));
+
+        let request = route_matches
+            && request_has_body
+            && (content_type.is_some_and(|ct| is_capturable_content_type(ct))
+                || grpc_method.is_some());
+
+        log::trace!(
+            "Body capture decision: route_matches={} request={}",
+            route_matches,
+            request
+        );
+
+        let content_encoding = event.request.headers.get("content-encoding");
+        let request_parts = content_type
+            .filter(|_| request && is_identity(content_encoding.map_or("", String::as_str)))
+            .and_then(|ct| MultipartSummary::for_content_type(Some(ct)));
+
+        BodyCapture {
+            request,
+            // The response content type isn't known yet, narrowed down on the response headers
+            response: route_matches,
+            request_parts,
+            grpc_method,
+            ..Default::default()
+        }
+    }
+
+    pub fn on_response_headers(&mut self, config: &Config, event: &Event) {
+        let content_type = event
+            .response
+            .as_ref()
+            .and_then(|response| response.headers.get("content-type"));
+        let textual = content_type.is_some_and(|ct| is_capturable_content_type(ct));
+        let grpc =
+            self.grpc_method.is_some() && content_type.is_some_and(|ct| is_grpc_content_type(ct));
+        self.response = self.response && (textual || grpc);
+
+        let event_stream = event
+            .response
+            .as_ref()
+            .and_then(|response| response.headers.get("content-type"))
+            .is_some_and(|ct| ct.to_ascii_lowercase().starts_with("text/event-stream"));
+        if self.response && event_stream {
+            // Streams that can't be decoded as they come in are captured like other bodies
+            let content_encoding = event
+                .response
+                .as_ref()
+                .and_then(|response| response.headers.get("content-encoding"));
+            self.response_events =
+                SseParser::for_content_encoding(content_encoding.map(String::as_str))
+                    .map(|parser| SseCapture::new(config.env.sse_max_events, parser));
+        }
+    }
+
+    // Envoy may ignore the mode override (allowModeOverride: false) and send bodies anyway,
+    // chunks for a direction that isn't captured are dropped
+    pub fn append_request_chunk(&mut self, config: &Config, chunk: &[u8]) {
+        if !self.request {
+            return;
+        }
+
+        match self.request_parts.as_mut() {
+            Some(parts) => parts.push(chunk, config.env.max_body_size),
+            None => self.request_buffer.append(chunk, config.env.max_body_size),
+        }
+    }
+
+    pub fn append_response_chunk(&mut self, config: &Config, chunk: &[u8]) {
+        if !self.response {
+            return;
+        }
+
+        match self.response_events.as_mut() {
+            Some(events) => events.push(chunk, config.env.max_body_size),
+            None => self.response_buffer.append(chunk, config.env.max_body_size),
+        }
+    }
+
+    // Ask Envoy to stream only the bodies that are captured. The override replaces the whole
+    // processing mode, so the trailer modes configured for the filter are carried over.
+    pub fn mode_override(&self, config: &Config) -> ProcessingMode {
+        let body_mode = |capture: bool| {
+            if capture {
+                BodySendMode::Streamed as i32
+            } else {
+                BodySendMode::None as i32
+            }
+        };
+
+        ProcessingMode {
+            request_header_mode: HeaderSendMode::Send as i32,
+            response_header_mode: HeaderSendMode::Send as i32,
+            request_body_mode: body_mode(self.request),
+            response_body_mode: body_mode(self.response),
+            request_trailer_mode: config.request_trailer_mode as i32,
+            response_trailer_mode: config.response_trailer_mode as i32,
+        }
+    }
+
+    pub fn apply_to_event(&mut self, config: &Config, event: &mut Event) {
+        if let Some(parts) = self.request_parts.as_mut() {
+            event.request.body = parts.finish();
+        } else if self.request && !self.request_buffer.data.is_empty() {
+            let content_type = event.request.headers.get("content-type").cloned();
+            let content_encoding = event.request.headers.get("content-encoding").cloned();
+            if let Some(data) = decode_content(
+                config,
+                event,
+                "request",
+                content_encoding,
+                &self.request_buffer.data,
+            ) {
+                let decoded = self
+                    .grpc_method
+                    .as_ref()
+                    .and_then(|method| decode_messages(&method.input(), &data));
+                let (body, encoding) = match decoded {
+                    Some(body) => (body, None),
+                    None => body_to_json(content_type.as_deref(), &data),
+                };
+                event.request.body = body;
+                if encoding.is_some() {
+                    event.request.transfer_encoding = encoding;
+                }
+            }
+        }
+        if self.request_buffer.omitted {
+            event.set_metadata("request_body_omitted", serde_json::Value::Bool(true));
+        }
+
+        if let Some(events) = self.response_events.as_mut() {
+            events.finish(config.env.max_body_size);
+            let content_encoding = event
+                .response
+                .as_ref()
+                .and_then(|response| response.headers.get("content-encoding"))
+                .filter(|encoding| !is_identity(encoding))
+                .cloned();
+            if let Some(content_encoding) = content_encoding {
+                event.set_metadata(
+                    "response_content_encoding",
+                    serde_json::Value::String(content_encoding),
+                );
+            }
+            if let Some(response) = event.response.as_mut() {
+                response.body = events.to_json();
+                response.transfer_encoding = None;
+            }
+            event.set_metadata("sse_event_count", serde_json::Value::from(events.count()));
+            if let Some(duration_ms) = events.duration_ms() {
+                event.set_metadata("sse_duration_ms", serde_json::Value::from(duration_ms));
+            }
+            return;
+        }
+
+        if self.response && !self.response_buffer.data.is_empty() {
+            let response_header = |name: &str| {
+                event
+                    .response
+                    .as_ref()
+                    .and_then(|response| response.headers.get(name).cloned())
+            };
+            let content_type = response_header("content-type");
+            let content_encoding = response_header("content-encoding");
+            let data = decode_content(
+                config,
+                event,
+                "response",
+                content_encoding,
+                &self.response_buffer.data,
+            );
+            if let (Some(data), Some(response)) = (data, event.response.as_mut()) {
+                let decoded = self
+                    .grpc_method
+                    .as_ref()
+                    .and_then(|method| decode_messages(&method.output(), &data));
+                let (body, encoding) = match decoded {
+                    Some(body) => (body, None),
+                    None => body_to_json(content_type.as_deref(), &data),
+                };
+                response.body = body;
+                response.transfer_encoding = encoding;
+            }
+        }
+        if self.response_buffer.omitted {
+            event.set_metadata("response_body_omitted", serde_json::Value::Bool(true));
+        }
+    }
+}
+
+// Readable content, and multipart forms which are summarized
+pub fn is_capturable_content_type(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+    mime.starts_with("text/")
+        || mime.ends_with("json")
+        || mime.ends_with("xml")
+        || mime == "application/x-www-form-urlencoded"
+        || mime == "application/graphql"
+        || mime == "application/javascript"
+        // Summarized without the contents of its parts
+        || mime == "multipart/form-data"
+}
+
+// Undo the Content-Encoding of a body, recording the encoding and the compressed size.
+// Bodies that decompress beyond the size limit are dropped like any other oversized body,
+// bodies that fail to decompress are kept as they are.
+fn decode_content<'a>(
+    config: &Config,
+    event: &mut Event,
+    direction: &str,
+    content_encoding: Option<String>,
+    data: &'a [u8],
+) -> Option<Cow<'a, [u8]>> {
+    let content_encoding = match content_encoding.filter(|encoding| !is_identity(encoding)) {
+        Some(content_encoding) => content_encoding,
+        None => return Some(Cow::Borrowed(data)),
+    };
+
+    event.set_metadata(
+        &format!("{}_content_encoding", direction),
+        serde_json::Value::String(content_encoding.clone()),
+    );
+    event.set_metadata(
+        &format!("{}_compressed_size", direction),
+        serde_json::Value::from(data.len()),
+    );
+
+    match decompress(&content_encoding, data, config.env.max_body_size) {
+        Ok(decompressed) => Some(Cow::Owned(decompressed)),
+        Err(DecompressError::TooLarge) => {
+            log::debug!(
+                "Decompressed {} body exceeds {} bytes, dropping it from the event",
+                direction,
+                config.env.max_body_size
+            );
+            event.set_metadata(
+                &format!("{}_body_omitted", direction),
+                serde_json::Value::Bool(true),
+            );
+            None
+        }
+        Err(e) => {
+            log::debug!("Failed to decompress {} body: {:?}", direction, e);
+            Some(Cow::Borrowed(data))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EnvConfig;
+    use crate::event::ResponseInfo;
+
+    #[test]
+    fn test_body_capture_follows_content_type() {
+        // arrange
+        let cfg = Config::new(EnvConfig {
+            log_body: true,
+            max_body_size: 16,
+            body_capture_paths: Some("^/orders".to_string()),
+            ..Default::default()
+        });
+        let mut event = Event::default();
+        event.request.uri = "/orders/1".to_string();
+        event
+            .request
+            .headers
+            .insert("content-type".to_string(), "application/json".to_string());
+        let mut response = ResponseInfo::default();
+        response
+            .headers
+            .insert("content-type".to_string(), "image/png".to_string());
+
+        // act
+        let mut capture = BodyCapture::for_request(&cfg, &event, true);
+        event.response = Some(response);
+        capture.on_response_headers(&cfg, &event);
+        capture.append_request_chunk(&cfg, b"{\"id\":");
+        capture.append_request_chunk(&cfg, b"1}");
+        capture.append_response_chunk(&cfg, b"\x89PNG");
+        capture.apply_to_event(&cfg, &mut event);
+
+        // assert
+        let mode = capture.mode_override(&cfg);
+        assert_eq!(mode.request_body_mode, BodySendMode::Streamed as i32);
+        assert_eq!(mode.response_body_mode, BodySendMode::None as i32);
+        assert_eq!(event.request.body, serde_json::json!({"id": 1}));
+        assert_eq!(event.response.unwrap().body, serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_body_capture_summarizes_large_uploads() {
+        // arrange
+        let cfg = Config::new(EnvConfig {
+            log_body: true,
+            max_body_size: 128,
+            ..Default::default()
+        });
+        let mut event = Event::default();
+        event.request.headers.insert(
+            "content-type".to_string(),
+            "multipart/form-data; boundary=XyZ".to_string(),
+        );
+        let file = vec![b'x'; 4096];
+
+        // act
+        let mut capture = BodyCapture::for_request(&cfg, &event, true);
+        capture.append_request_chunk(
+            &cfg,
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.bin\"\r\n\r\n",
+        );
+        for chunk in file.chunks(100) {
+            capture.append_request_chunk(&cfg, chunk);
+        }
+        capture.append_request_chunk(&cfg, b"\r\n--XyZ--\r\n");
+        capture.apply_to_event(&cfg, &mut event);
+
+        // assert
+        assert_eq!(
+            event.request.body,
+            serde_json::json!({"parts": [{"name": "file", "filename": "a.bin", "size": 4096}]})
+        );
+        assert_eq!(event.request.transfer_encoding, None);
+        assert_eq!(event.metadata.get("request_body_omitted"), None);
+    }
+}
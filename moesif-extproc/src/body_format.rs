@@ -0,0 +1,378 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use encoding_rs::{Encoding, UTF_8};
+use serde_json::{json, Map, Value};
+
+// How a captured body is represented in the event, chosen from its content type
+#[derive(Debug, PartialEq)]
+enum BodyFormat {
+    Json,
+    Form,
+    Multipart(String),
+    Text(&'static Encoding),
+    Binary,
+}
+
+impl BodyFormat {
+    fn for_content_type(content_type: Option<&str>) -> Self {
+        let content_type = content_type.unwrap_or_default();
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        // Parameter values such as the multipart boundary are case sensitive
+        let param = |name: &str| {
+            content_type.split(';').skip(1).find_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case(name)
+                    .then(|| value.trim().trim_matches('"').to_string())
+            })
+        };
+
+        if mime.ends_with("json") {
+            BodyFormat::Json
+        } else if mime == "application/x-www-form-urlencoded" {
+            BodyFormat::Form
+        } else if mime == "multipart/form-data" {
+            match param("boundary") {
+                Some(boundary) => BodyFormat::Multipart(boundary),
+                None => BodyFormat::Binary,
+            }
+        } else if mime.starts_with("text/")
+            || mime.ends_with("xml")
+            || mime == "application/graphql"
+            || mime == "application/javascript"
+        {
+            let encoding = param("charset")
+                .and_then(|charset| Encoding::for_label(charset.as_bytes()))
+                .unwrap_or(UTF_8);
+            BodyFormat::Text(encoding)
+        } else if content_type.is_empty() {
+            // Without a content type, JSON is the most likely
+            BodyFormat::Json
+        } else {
+            BodyFormat::Binary
+        }
+    }
+}
+
+// The body as it is sent to Moesif and its transfer encoding, "base64" when the body isn't
+// readable as is
+pub fn body_to_json(content_type: Option<&str>, data: &[u8]) -> (Value, Option<String>) {
+    let value = match BodyFormat::for_content_type(content_type) {
+        BodyFormat::Json => serde_json::from_slice::<Value>(data).ok(),
+        BodyFormat::Form => Some(form_to_json(data)),
+        BodyFormat::Multipart(boundary) => {
+            let mut summary = MultipartSummary::new(&boundary);
+            summary.push(data, data.len());
+            Some(summary.finish())
+        }
+        BodyFormat::Text(encoding) => {
+            let (text, _, had_errors) = encoding.decode(data);
+            (!had_errors).then(|| Value::String(text.into_owned()))
+        }
+        BodyFormat::Binary => None,
+    };
+
+    match value {
+        Some(value) => (value, None),
+        None => (
+            Value::String(STANDARD.encode(data)),
+            Some("base64".to_string()),
+        ),
+    }
+}
+
+// Keys sent more than once become lists
+fn form_to_json(data: &[u8]) -> Value {
+    let mut form = Map::new();
+    for (key, value) in url::form_urlencoded::parse(data) {
+        let value = Value::String(value.into_owned());
+        match form.get_mut(key.as_ref()) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                form.insert(key.into_owned(), value);
+            }
+        }
+    }
+    Value::Object(form)
+}
+
+// Longest header section of a part, parts with longer headers end the summary
+const MAX_PART_HEADERS_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, PartialEq)]
+enum MultipartState {
+    Preamble,
+    Delimiter,
+    Headers,
+    Content,
+    Done,
+}
+
+// Describe the parts of a multipart/form-data body by name, file name, content type and
+// size as it streams in. Part contents are counted, not kept, so uploads of any size are
+// summarized; only the part headers count towards the size limit.
+#[derive(Debug)]
+pub struct MultipartSummary {
+    // "\r\n--<boundary>", the line break belongs to the delimiter
+    delimiter: Vec<u8>,
+    state: MultipartState,
+    // Bytes that can't be classified yet, such as a delimiter split across chunks
+    pending: Vec<u8>,
+    parts: Vec<Value>,
+    part_size: usize,
+    headers_size: usize,
+    // Set once the closing delimiter was seen
+    complete: bool,
+}
+
+impl MultipartSummary {
+    pub fn for_content_type(content_type: Option<&str>) -> Option<Self> {
+        match BodyFormat::for_content_type(content_type) {
+            BodyFormat::Multipart(boundary) => Some(MultipartSummary::new(&boundary)),
+            _ => None,
+        }
+    }
+
+    fn new(boundary: &str) -> Self {
+        MultipartSummary {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            state: MultipartState::Preamble,
+            // The first delimiter may start the body without a line break
+            pending: b"\r\n".to_vec(),
+            parts: Vec::new(),
+            part_size: 0,
+            headers_size: 0,
+            complete: false,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8], max_size: usize) {
+        if self.state == MultipartState::Done {
+            return;
+        }
+        self.pending.extend_from_slice(chunk);
+
+        loop {
+            let progressed = match self.state {
+                MultipartState::Preamble | MultipartState::Content => self.scan_content(),
+                MultipartState::Delimiter => self.scan_delimiter(),
+                MultipartState::Headers => self.scan_headers(max_size),
+                MultipartState::Done => {
+                    self.pending = Vec::new();
+                    false
+                }
+            };
+            if !progressed {
+                break;
+            }
+        }
+    }
+
+    // Count the content up to the next delimiter, keeping what may be the start of one
+    fn scan_content(&mut self) -> bool {
+        match find(&self.pending, &self.delimiter, 0) {
+            Some(end) => {
+                self.part_size += end;
+                self.end_part();
+                self.pending.drain(..end + self.delimiter.len());
+                self.state = MultipartState::Delimiter;
+                true
+            }
+            None => {
+                let counted = self.pending.len().saturating_sub(self.delimiter.len() - 1);
+                self.part_size += counted;
+                self.pending.drain(..counted);
+                false
+            }
+        }
+    }
+
+    // A delimiter is followed by "--" for the last one, or by the end of its line
+    fn scan_delimiter(&mut self) -> bool {
+        if self.pending.starts_with(b"--") {
+            self.complete = true;
+            self.state = MultipartState::Done;
+            return true;
+        }
+        match find(&self.pending, b"\r\n", 0) {
+            Some(end) => {
+                self.pending.drain(..end + 2);
+                self.state = MultipartState::Headers;
+                true
+            }
+            None => {
+                if self.pending.len() > MAX_PART_HEADERS_SIZE {
+                    self.state = MultipartState::Done;
+                    return true;
+                }
+                false
+            }
+        }
+    }
+
+    fn scan_headers(&mut self, max_size: usize) -> bool {
+        let end = if self.pending.starts_with(b"\r\n") {
+            Some(0)
+        } else {
+            find(&self.pending, b"\r\n\r\n", 0).map(|end| end + 2)
+        };
+        let end = match end {
+            Some(end) => end,
+            None => {
+                if self.pending.len() > MAX_PART_HEADERS_SIZE {
+                    log::debug!("Multipart part headers are too large, ending the summary");
+                    self.state = MultipartState::Done;
+                    return true;
+                }
+                return false;
+            }
+        };
+
+        self.headers_size += end;
+        if self.headers_size > max_size {
+            log::debug!(
+                "Multipart part headers exceed {} bytes, ending the summary",
+                max_size
+            );
+            self.state = MultipartState::Done;
+            return true;
+        }
+        let headers = String::from_utf8_lossy(&self.pending[..end]).into_owned();
+        self.parts.push(part_summary(&headers));
+        self.part_size = 0;
+        self.pending.drain(..end + 2);
+        self.state = MultipartState::Content;
+        true
+    }
+
+    fn end_part(&mut self) {
+        if self.state == MultipartState::Content {
+            if let Some(part) = self.parts.last_mut() {
+                part["size"] = Value::from(self.part_size);
+            }
+        }
+    }
+
+    // The parts seen so far, a body that ended before its closing delimiter is flagged as
+    // incomplete
+    pub fn finish(&mut self) -> Value {
+        if self.state == MultipartState::Content {
+            // Held back in case a delimiter started there, the body ended instead
+            self.part_size += self.pending.len();
+            self.pending.clear();
+        }
+        self.end_part();
+        let mut summary = json!({ "parts": self.parts });
+        if !self.complete {
+            summary["incomplete"] = Value::Bool(true);
+        }
+        summary
+    }
+}
+
+fn part_summary(headers: &str) -> Value {
+    let mut summary = json!({ "size": 0 });
+
+    for header in headers.split("\r\n") {
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => continue,
+        };
+        match name.as_str() {
+            "content-disposition" => {
+                for param in value.split(';').skip(1) {
+                    if let Some((key, value)) = param.split_once('=') {
+                        let key = key.trim();
+                        if key == "name" || key == "filename" {
+                            summary[key] =
+                                Value::String(value.trim().trim_matches('"').to_string());
+                        }
+                    }
+                }
+            }
+            "content-type" => summary["content_type"] = Value::String(value.to_string()),
+            _ => {}
+        }
+    }
+
+    summary
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| position + from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body_to_json_by_content_type() {
+        // arrange
+        let multipart = b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n\
+            --XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\
+            Content-Type: image/png\r\n\r\n\x89PNG\r\n\x1a\n\r\n--XyZ--\r\n";
+
+        // act
+        let form = body_to_json(
+            Some("application/x-www-form-urlencoded"),
+            b"tag=a&tag=b&q=hello+world",
+        );
+        let parts = body_to_json(Some("Multipart/Form-Data; boundary=\"XyZ\""), multipart);
+        let latin1 = body_to_json(Some("text/plain; charset=ISO-8859-1"), b"caf\xe9");
+        let binary = body_to_json(Some("application/octet-stream"), b"\x00\x01");
+
+        // assert
+        assert_eq!(form.0, json!({"tag": ["a", "b"], "q": "hello world"}));
+        assert_eq!(
+            parts.0,
+            json!({"parts": [
+                {"name": "title", "size": 5},
+                {"name": "file", "filename": "a.png", "content_type": "image/png", "size": 8},
+            ]})
+        );
+        assert_eq!(latin1, (json!("café"), None));
+        assert_eq!(binary, (json!("AAE="), Some("base64".to_string())));
+    }
+
+    #[test]
+    fn test_multipart_summary_streams_parts() {
+        // arrange
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\none\r\n\
+            --XyZ\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"big.bin\"\r\n\r\n\
+            0123456789012345678901234567890123456789";
+        let mut streamed =
+            MultipartSummary::for_content_type(Some("multipart/form-data; boundary=XyZ")).unwrap();
+        let mut cut_short =
+            MultipartSummary::for_content_type(Some("multipart/form-data; boundary=XyZ")).unwrap();
+
+        // act
+        for chunk in body.chunks(3) {
+            streamed.push(chunk, 1000);
+        }
+        streamed.push(b"\r\n--XyZ--\r\n", 1000);
+        cut_short.push(body, 1000);
+
+        // assert
+        let parts = json!([
+            {"name": "a", "size": 3},
+            {"name": "upload", "filename": "big.bin", "size": 40},
+        ]);
+        assert_eq!(streamed.finish(), json!({ "parts": parts }));
+        assert_eq!(
+            cut_short.finish(),
+            json!({ "parts": parts, "incomplete": true })
+        );
+        assert!(streamed.pending.is_empty());
+    }
+}
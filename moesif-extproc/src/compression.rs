@@ -0,0 +1,190 @@
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use flate2::write;
+use std::io::{Read, Write};
+
+#[derive(Debug, PartialEq)]
+pub enum DecompressError {
+    // The decompressed body is larger than the size limit
+    TooLarge,
+    UnsupportedEncoding(String),
+    Invalid(String),
+}
+
+// Undo the content codings listed in a Content-Encoding header. Codings are listed in the
+// order they were applied, so they are removed from last to first. Output is bounded by
+// max_size so a small body can't expand without limit.
+pub fn decompress(
+    content_encoding: &str,
+    data: &[u8],
+    max_size: usize,
+) -> Result<Vec<u8>, DecompressError> {
+    let mut body = data.to_vec();
+
+    for coding in content_encoding.split(',').rev() {
+        let coding = coding.trim().to_ascii_lowercase();
+        body = match coding.as_str() {
+            "" | "identity" => body,
+            "gzip" | "x-gzip" => read_bounded(GzDecoder::new(body.as_slice()), max_size)?,
+            // Deflate is meant to be zlib wrapped, but some servers send raw deflate
+            "deflate" => read_bounded(ZlibDecoder::new(body.as_slice()), max_size)
+                .or_else(|_| read_bounded(DeflateDecoder::new(body.as_slice()), max_size))?,
+            "br" => read_bounded(
+                brotli_decompressor::Decompressor::new(body.as_slice(), 4096),
+                max_size,
+            )?,
+            _ => return Err(DecompressError::UnsupportedEncoding(coding)),
+        };
+    }
+
+    Ok(body)
+}
+
+pub fn is_identity(content_encoding: &str) -> bool {
+    content_encoding
+        .split(',')
+        .all(|coding| coding.trim().is_empty() || coding.trim().eq_ignore_ascii_case("identity"))
+}
+
+fn read_bounded(reader: impl Read, max_size: usize) -> Result<Vec<u8>, DecompressError> {
+    let mut output = Vec::new();
+    reader
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(|e| DecompressError::Invalid(e.to_string()))?;
+
+    if output.len() > max_size {
+        return Err(DecompressError::TooLarge);
+    }
+    Ok(output)
+}
+
+// Input is fed to the decoder in slices this small, so the output of a single write stays
+// bounded (deflate expands at most about 1000 times)
+const STREAM_SLICE_SIZE: usize = 1024;
+
+enum StreamWriter {
+    Gzip(write::GzDecoder<Vec<u8>>),
+    Zlib(write::ZlibDecoder<Vec<u8>>),
+    Brotli(Box<brotli_decompressor::DecompressorWriter<Vec<u8>>>),
+}
+
+// Decodes a body chunk by chunk as it arrives, for streams that aren't buffered such as
+// Server-Sent Events. Only a single coding is supported.
+pub struct StreamDecoder {
+    writer: StreamWriter,
+}
+
+impl std::fmt::Debug for StreamDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StreamDecoder")
+    }
+}
+
+impl StreamDecoder {
+    pub fn new(content_encoding: &str) -> Result<Self, DecompressError> {
+        let coding = content_encoding.trim().to_ascii_lowercase();
+        let writer = match coding.as_str() {
+            "gzip" | "x-gzip" => StreamWriter::Gzip(write::GzDecoder::new(Vec::new())),
+            "deflate" => StreamWriter::Zlib(write::ZlibDecoder::new(Vec::new())),
+            "br" => StreamWriter::Brotli(Box::new(brotli_decompressor::DecompressorWriter::new(
+                Vec::new(),
+                4096,
+            ))),
+            _ => return Err(DecompressError::UnsupportedEncoding(coding)),
+        };
+        Ok(StreamDecoder { writer })
+    }
+
+    // The decoded bytes of this chunk, an error when they exceed max_size
+    pub fn decode(&mut self, chunk: &[u8], max_size: usize) -> Result<Vec<u8>, DecompressError> {
+        let mut output = Vec::new();
+        for slice in chunk.chunks(STREAM_SLICE_SIZE) {
+            self.write(slice)?;
+            output.append(self.output());
+            if output.len() > max_size {
+                return Err(DecompressError::TooLarge);
+            }
+        }
+        Ok(output)
+    }
+
+    // Whatever the decoder still holds once the stream ended
+    pub fn finish(&mut self) -> Result<Vec<u8>, DecompressError> {
+        let result = match &mut self.writer {
+            StreamWriter::Gzip(decoder) => decoder.try_finish(),
+            StreamWriter::Zlib(decoder) => decoder.try_finish(),
+            StreamWriter::Brotli(decoder) => decoder.close(),
+        };
+        result.map_err(|e| DecompressError::Invalid(e.to_string()))?;
+        Ok(std::mem::take(self.output()))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), DecompressError> {
+        let result = match &mut self.writer {
+            StreamWriter::Gzip(decoder) => decoder.write_all(data).and_then(|_| decoder.flush()),
+            StreamWriter::Zlib(decoder) => decoder.write_all(data).and_then(|_| decoder.flush()),
+            StreamWriter::Brotli(decoder) => decoder.write_all(data).and_then(|_| decoder.flush()),
+        };
+        result.map_err(|e| DecompressError::Invalid(e.to_string()))
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match &mut self.writer {
+            StreamWriter::Gzip(decoder) => decoder.get_mut(),
+            StreamWriter::Zlib(decoder) => decoder.get_mut(),
+            StreamWriter::Brotli(decoder) => decoder.get_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn test_decompress_is_bounded() {
+        // arrange
+        let body = vec![b'a'; 10_000];
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        // act
+        let decompressed = decompress("gzip", &gzipped, 10_000);
+        let too_large = decompress("gzip", &gzipped, 9_999);
+        let unsupported = decompress("zstd", &gzipped, 10_000);
+
+        // assert
+        assert_eq!(decompressed, Ok(body));
+        assert_eq!(too_large, Err(DecompressError::TooLarge));
+        assert_eq!(
+            unsupported,
+            Err(DecompressError::UnsupportedEncoding("zstd".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_stream_decoder_decodes_chunks() {
+        // arrange
+        let body = b"data: 1\n\ndata: 2\n\n".repeat(100);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let mut decoder = StreamDecoder::new("gzip").unwrap();
+
+        // act
+        let mut decoded = Vec::new();
+        for chunk in gzipped.chunks(7) {
+            decoded.extend(decoder.decode(chunk, 10_000).unwrap());
+        }
+        decoded.extend(decoder.finish().unwrap());
+        let too_large = StreamDecoder::new("gzip").unwrap().decode(&gzipped, 100);
+
+        // assert
+        assert_eq!(decoded, body);
+        assert_eq!(too_large, Err(DecompressError::TooLarge));
+    }
+}
@@ -1,17 +1,169 @@
+use envoy_ext_proc_proto::envoy::extensions::filters::http::ext_proc::v3::processing_mode::HeaderSendMode;
+use ipnet::IpNet;
+use jsonwebtoken::jwk::JwkSet;
+use prost_reflect::DescriptorPool;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, env};
+use std::{collections::HashMap, env, net::IpAddr};
+
+use crate::application_id::{parse_application_id_routes, ApplicationIdRoute};
+use crate::grpc_payload::load_descriptor_pool;
+use crate::id_source::{parse_id_sources, IdSource};
+use crate::jwt::load_jwks;
 
 #[derive(Default, Clone)]
 pub struct Config {
     pub env: EnvConfig,
+    pub jwks: Option<JwkSet>,
+    pub user_id_sources: Vec<IdSource>,
+    pub company_id_sources: Vec<IdSource>,
+    pub body_capture_paths: Vec<Regex>,
+    pub graphql_paths: Vec<Regex>,
+    pub llm_paths: Vec<Regex>,
+    pub application_id_routes: Vec<ApplicationIdRoute>,
+    pub grpc_descriptors: Option<DescriptorPool>,
+    pub request_trailer_mode: HeaderSendMode,
+    pub response_trailer_mode: HeaderSendMode,
     // pub _event_queue_id: u32,
 }
 
+impl Config {
+    pub fn new(env: EnvConfig) -> Self {
+        let jwks = env.jwks_path.as_deref().and_then(load_jwks);
+        let user_id_sources = env
+            .user_id_sources
+            .as_deref()
+            .map(parse_id_sources)
+            .unwrap_or_default();
+        let company_id_sources = env
+            .company_id_sources
+            .as_deref()
+            .map(parse_id_sources)
+            .unwrap_or_default();
+        let body_capture_paths = env
+            .body_capture_paths
+            .as_deref()
+            .map(Self::parse_path_patterns)
+            .unwrap_or_default();
+        let graphql_paths = env
+            .graphql_paths
+            .as_deref()
+            .map(Self::parse_path_patterns)
+            .unwrap_or_default();
+        let llm_paths = env
+            .llm_paths
+            .as_deref()
+            .map(Self::parse_path_patterns)
+            .unwrap_or_default();
+        let application_id_routes = env
+            .application_id_routes
+            .as_deref()
+            .map(parse_application_id_routes)
+            .unwrap_or_default();
+        let grpc_descriptors = env
+            .grpc_descriptor_set_path
+            .as_deref()
+            .and_then(load_descriptor_pool);
+        let request_trailer_mode = Self::parse_send_mode(&env.request_trailer_mode);
+        let response_trailer_mode = Self::parse_send_mode(&env.response_trailer_mode);
+
+        Config {
+            env,
+            jwks,
+            user_id_sources,
+            company_id_sources,
+            body_capture_paths,
+            graphql_paths,
+            llm_paths,
+            application_id_routes,
+            grpc_descriptors,
+            request_trailer_mode,
+            response_trailer_mode,
+        }
+    }
+
+    // An Envoy processing mode name, "SEND" or "SKIP"
+    fn parse_send_mode(value: &str) -> HeaderSendMode {
+        match HeaderSendMode::from_str_name(&value.trim().to_ascii_uppercase()) {
+            Some(mode) => mode,
+            None => {
+                if !value.trim().is_empty() {
+                    log::error!("Ignoring invalid processing mode {:?}, using SKIP", value);
+                }
+                HeaderSendMode::Skip
+            }
+        }
+    }
+
+    fn parse_path_patterns(value: &str) -> Vec<Regex> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .filter_map(|v| match Regex::new(v) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    log::error!("Ignoring invalid path pattern {:?}: {}", v, e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct EnvConfig {
     pub moesif_application_id: String,
     pub user_id_header: Option<String>,
     pub company_id_header: Option<String>,
+    pub user_id_sources: Option<String>,
+    pub company_id_sources: Option<String>,
+    pub session_token_header: Option<String>,
+    pub session_token_cookie: Option<String>,
+    #[serde(default = "default_hash_session_token")]
+    pub hash_session_token: bool,
+    #[serde(default = "default_authorization_header_name")]
+    pub authorization_header_name: String,
+    pub authorization_cookie_name: Option<String>,
+    pub authorization_user_id_field: Option<String>,
+    pub authorization_company_id_field: Option<String>,
+    pub jwks_path: Option<String>,
+    pub user_id_metadata: Option<String>,
+    pub company_id_metadata: Option<String>,
+    pub session_token_metadata: Option<String>,
+    #[serde(default = "default_xff_trusted_hops")]
+    pub xff_trusted_hops: usize,
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    #[serde(default = "default_observability_mode")]
+    pub observability_mode: bool,
+    #[serde(default = "default_log_body")]
+    pub log_body: bool,
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    pub body_capture_paths: Option<String>,
+    #[serde(default = "default_sse_max_events")]
+    pub sse_max_events: usize,
+    pub grpc_descriptor_set_path: Option<String>,
+    pub graphql_paths: Option<String>,
+    #[serde(default = "default_graphql_rewrite_uri")]
+    pub graphql_rewrite_uri: bool,
+    #[serde(default = "default_llm_usage")]
+    pub llm_usage: bool,
+    pub llm_paths: Option<String>,
+    #[serde(default = "default_route_policy_namespace")]
+    pub route_policy_namespace: String,
+    pub application_id_routes: Option<String>,
+    #[serde(default = "default_emit_dynamic_metadata")]
+    pub emit_dynamic_metadata: bool,
+    #[serde(default = "default_trailer_mode")]
+    pub request_trailer_mode: String,
+    #[serde(default = "default_trailer_mode")]
+    pub response_trailer_mode: String,
+    #[serde(default = "default_stream_idle_timeout")]
+    pub stream_idle_timeout: usize,
+    #[serde(default = "default_stream_max_duration")]
+    pub stream_max_duration: usize,
     #[serde(default = "default_batch_max_size")]
     pub batch_max_size: usize,
     #[serde(default = "default_batch_max_wait")]
@@ -26,6 +178,62 @@ pub struct EnvConfig {
     pub rust_log: Option<String>,
 }
 
+fn default_hash_session_token() -> bool {
+    false
+}
+
+fn default_authorization_header_name() -> String {
+    "authorization".to_string()
+}
+
+fn default_xff_trusted_hops() -> usize {
+    0
+}
+
+fn default_observability_mode() -> bool {
+    false
+}
+
+fn default_log_body() -> bool {
+    false
+}
+
+fn default_max_body_size() -> usize {
+    100_000
+}
+
+fn default_sse_max_events() -> usize {
+    20
+}
+
+fn default_graphql_rewrite_uri() -> bool {
+    false
+}
+
+fn default_llm_usage() -> bool {
+    false
+}
+
+fn default_route_policy_namespace() -> String {
+    "moesif".to_string()
+}
+
+fn default_emit_dynamic_metadata() -> bool {
+    false
+}
+
+fn default_trailer_mode() -> String {
+    "SKIP".to_string()
+}
+
+fn default_stream_idle_timeout() -> usize {
+    0
+}
+
+fn default_stream_max_duration() -> usize {
+    0
+}
+
 fn default_batch_max_size() -> usize {
     100
 }
@@ -86,6 +294,71 @@ impl EnvConfig {
             env::var("MOESIF_APPLICATION_ID").unwrap_or_else(|_| String::new());
         let user_id_header = env::var("USER_ID_HEADER").ok();
         let company_id_header = env::var("COMPANY_ID_HEADER").ok();
+        let user_id_sources = env::var("USER_ID_SOURCES").ok();
+        let company_id_sources = env::var("COMPANY_ID_SOURCES").ok();
+        let session_token_header = env::var("SESSION_TOKEN_HEADER").ok();
+        let session_token_cookie = env::var("SESSION_TOKEN_COOKIE").ok();
+        let hash_session_token = env::var("HASH_SESSION_TOKEN")
+            .ok()
+            .map_or_else(default_hash_session_token, |v| v == "true");
+        let authorization_header_name = env::var("AUTHORIZATION_HEADER_NAME")
+            .unwrap_or_else(|_| default_authorization_header_name());
+        let authorization_cookie_name = env::var("AUTHORIZATION_COOKIE_NAME").ok();
+        let authorization_user_id_field = env::var("AUTHORIZATION_USER_ID_FIELD").ok();
+        let authorization_company_id_field = env::var("AUTHORIZATION_COMPANY_ID_FIELD").ok();
+        let jwks_path = env::var("JWKS_PATH").ok();
+        let user_id_metadata = env::var("USER_ID_METADATA").ok();
+        let company_id_metadata = env::var("COMPANY_ID_METADATA").ok();
+        let session_token_metadata = env::var("SESSION_TOKEN_METADATA").ok();
+        let xff_trusted_hops = env::var("XFF_TRUSTED_HOPS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or_else(default_xff_trusted_hops);
+        let trusted_proxies = env::var("TRUSTED_PROXIES")
+            .map(|v| Self::parse_trusted_proxies(&v))
+            .unwrap_or_default();
+        let observability_mode = env::var("OBSERVABILITY_MODE")
+            .ok()
+            .map_or_else(default_observability_mode, |v| v == "true");
+        let log_body = env::var("LOG_BODY")
+            .ok()
+            .map_or_else(default_log_body, |v| v == "true");
+        let max_body_size = env::var("MAX_BODY_SIZE")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or_else(default_max_body_size);
+        let body_capture_paths = env::var("BODY_CAPTURE_PATHS").ok();
+        let sse_max_events = env::var("SSE_MAX_EVENTS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or_else(default_sse_max_events);
+        let grpc_descriptor_set_path = env::var("GRPC_DESCRIPTOR_SET_PATH").ok();
+        let graphql_paths = env::var("GRAPHQL_PATHS").ok();
+        let graphql_rewrite_uri = env::var("GRAPHQL_REWRITE_URI")
+            .ok()
+            .map_or_else(default_graphql_rewrite_uri, |v| v == "true");
+        let llm_usage = env::var("LLM_USAGE")
+            .ok()
+            .map_or_else(default_llm_usage, |v| v == "true");
+        let llm_paths = env::var("LLM_PATHS").ok();
+        let route_policy_namespace =
+            env::var("ROUTE_POLICY_NAMESPACE").unwrap_or_else(|_| default_route_policy_namespace());
+        let application_id_routes = env::var("APPLICATION_ID_ROUTES").ok();
+        let emit_dynamic_metadata = env::var("EMIT_DYNAMIC_METADATA")
+            .ok()
+            .map_or_else(default_emit_dynamic_metadata, |v| v == "true");
+        let request_trailer_mode =
+            env::var("REQUEST_TRAILER_MODE").unwrap_or_else(|_| default_trailer_mode());
+        let response_trailer_mode =
+            env::var("RESPONSE_TRAILER_MODE").unwrap_or_else(|_| default_trailer_mode());
+        let stream_idle_timeout = env::var("STREAM_IDLE_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or_else(default_stream_idle_timeout);
+        let stream_max_duration = env::var("STREAM_MAX_DURATION")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or_else(default_stream_max_duration);
         let batch_max_size = env::var("BATCH_MAX_SIZE")
             .ok()
             .and_then(|v| v.parse::<usize>().ok())
@@ -115,6 +388,38 @@ impl EnvConfig {
             moesif_application_id,
             user_id_header,
             company_id_header,
+            user_id_sources,
+            company_id_sources,
+            session_token_header,
+            session_token_cookie,
+            hash_session_token,
+            authorization_header_name,
+            authorization_cookie_name,
+            authorization_user_id_field,
+            authorization_company_id_field,
+            jwks_path,
+            user_id_metadata,
+            company_id_metadata,
+            session_token_metadata,
+            xff_trusted_hops,
+            trusted_proxies,
+            observability_mode,
+            log_body,
+            max_body_size,
+            body_capture_paths,
+            sse_max_events,
+            grpc_descriptor_set_path,
+            graphql_paths,
+            graphql_rewrite_uri,
+            llm_usage,
+            llm_paths,
+            route_policy_namespace,
+            application_id_routes,
+            emit_dynamic_metadata,
+            request_trailer_mode,
+            response_trailer_mode,
+            stream_idle_timeout,
+            stream_max_duration,
             batch_max_size,
             batch_max_wait,
             upstream,
@@ -129,6 +434,25 @@ impl EnvConfig {
         config
     }
 
+    fn parse_trusted_proxies(value: &str) -> Vec<IpNet> {
+        // CIDR ranges or single addresses, e.g. "10.0.0.0/8,192.168.1.10"
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .filter_map(|v| {
+                let network = v
+                    .parse::<IpNet>()
+                    .ok()
+                    .or_else(|| v.parse::<IpAddr>().ok().map(IpNet::from));
+                if network.is_none() {
+                    log::error!("Ignoring invalid trusted proxy {:?}", v);
+                }
+                network
+            })
+            .collect()
+    }
+
     fn parse_upstream_url(upstream: &str) -> Result<String, ()> {
         // Logic to parse the upstream string and extract base_uri
         // Example logic assuming the upstream format: "outbound|443||api.moesif.net"
@@ -1,6 +1,10 @@
 use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
 
-use serde::{Deserialize, Serialize};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub const SET_COOKIE_HEADER: &str = "set-cookie";
 
 #[derive(Default, Serialize, Deserialize, Clone)]
 pub struct RequestInfo {
@@ -18,11 +22,80 @@ pub struct RequestInfo {
 pub struct ResponseInfo {
     pub time: String,
     pub status: usize,
-    pub headers: HashMap<String, String>,
+    #[serde(
+        serialize_with = "serialize_response_headers",
+        deserialize_with = "deserialize_response_headers"
+    )]
+    pub headers: ResponseHeaders,
+    pub transfer_encoding: Option<String>,
     pub ip_address: Option<String>,
     pub body: serde_json::Value,
 }
 
+// Response headers by name, along with every set-cookie value since they can't be combined
+// into one header. The map holds the first one.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct ResponseHeaders {
+    pub values: HashMap<String, String>,
+    pub set_cookies: Vec<String>,
+}
+
+impl Deref for ResponseHeaders {
+    type Target = HashMap<String, String>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.values
+    }
+}
+
+impl DerefMut for ResponseHeaders {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.values
+    }
+}
+
+// Repeated set-cookie values are sent as a list
+fn serialize_response_headers<S: Serializer>(
+    headers: &ResponseHeaders,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(headers.len()))?;
+    for (key, value) in headers.iter() {
+        if key == SET_COOKIE_HEADER && headers.set_cookies.len() > 1 {
+            map.serialize_entry(key, &headers.set_cookies)?;
+        } else {
+            map.serialize_entry(key, value)?;
+        }
+    }
+    map.end()
+}
+
+fn deserialize_response_headers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<ResponseHeaders, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum HeaderValues {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let mut headers = ResponseHeaders::default();
+    for (key, values) in HashMap::<String, HeaderValues>::deserialize(deserializer)? {
+        let values = match values {
+            HeaderValues::One(value) => vec![value],
+            HeaderValues::Many(values) => values,
+        };
+        if let Some(first) = values.first() {
+            headers.values.insert(key.clone(), first.clone());
+        }
+        if key == SET_COOKIE_HEADER {
+            headers.set_cookies = values;
+        }
+    }
+    Ok(headers)
+}
+
 #[derive(Default, Serialize, Deserialize, Clone)]
 pub struct Event {
     pub request: RequestInfo,
@@ -34,3 +107,13 @@ pub struct Event {
     pub session_token: Option<String>,
     pub blocked_by: Option<String>,
 }
+
+impl Event {
+    // Metadata starts out as null, turn it into an object on first use
+    pub fn set_metadata(&mut self, key: &str, value: serde_json::Value) {
+        if !self.metadata.is_object() {
+            self.metadata = serde_json::Value::Object(serde_json::Map::new());
+        }
+        self.metadata[key] = value;
+    }
+}
@@ -0,0 +1,344 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::body::BodyBuffer;
+use crate::config::Config;
+use crate::event::Event;
+
+// The operation of a GraphQL request
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct GraphqlOperation {
+    pub operation_name: Option<String>,
+    pub operation_type: Option<String>,
+    pub query_hash: Option<String>,
+}
+
+impl GraphqlOperation {
+    // Read the operation from a request body or the query string of a GET request, both
+    // carrying `query`, `operationName` and, for persisted queries, `extensions`
+    pub fn from_request(request: &Value) -> Option<Self> {
+        // Batched requests, describe the first operation
+        let request = match request.as_array() {
+            Some(batch) => batch.first()?,
+            None => request,
+        };
+
+        let operation_name = request
+            .get("operationName")
+            .and_then(Value::as_str)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string);
+        let query = request.get("query").and_then(Value::as_str);
+        let persisted_query_hash = request
+            .pointer("/extensions/persistedQuery/sha256Hash")
+            .and_then(Value::as_str);
+        if query.is_none() && persisted_query_hash.is_none() {
+            return None;
+        }
+
+        let tokens = query.map(tokenize).unwrap_or_default();
+        let (operation_type, document_name) = find_operation(&tokens, operation_name.as_deref())
+            .map(|(operation_type, name)| (Some(operation_type.to_string()), name))
+            .unwrap_or_default();
+        let query_hash = match query {
+            Some(_) => Some(format!("{:x}", Sha256::digest(normalize(&tokens)))),
+            None => persisted_query_hash.map(str::to_string),
+        };
+
+        Some(GraphqlOperation {
+            operation_name: operation_name.or(document_name),
+            operation_type,
+            query_hash,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "operation_name": self.operation_name,
+            "operation_type": self.operation_type,
+            "query_hash": self.query_hash,
+        })
+    }
+}
+
+// Reads the GraphQL operation of requests to the configured GraphQL endpoints
+#[derive(Debug, Default)]
+pub struct GraphqlCapture {
+    buffer: BodyBuffer,
+}
+
+impl GraphqlCapture {
+    pub fn for_request(config: &Config, uri: &str) -> Option<Self> {
+        let path = uri.split('?').next().unwrap_or_default();
+        config
+            .graphql_paths
+            .iter()
+            .any(|regex| regex.is_match(path))
+            .then(GraphqlCapture::default)
+    }
+
+    pub fn append_chunk(&mut self, config: &Config, chunk: &[u8]) {
+        self.buffer.append(chunk, config.env.max_body_size);
+    }
+
+    pub fn apply_to_event(&self, config: &Config, event: &mut Event) {
+        let raw_document = event
+            .request
+            .headers
+            .get("content-type")
+            .is_some_and(|ct| ct.to_ascii_lowercase().starts_with("application/graphql"));
+        let request = if raw_document {
+            // The body is the document itself, the operation name can be in the query string
+            let mut request = query_string_request(&event.request.uri);
+            request["query"] = Value::String(String::from_utf8_lossy(&self.buffer.data).into());
+            request
+        } else {
+            match serde_json::from_slice::<Value>(&self.buffer.data) {
+                Ok(body) => body,
+                // GET requests send the operation in the query string
+                Err(_) => query_string_request(&event.request.uri),
+            }
+        };
+
+        let operation = match GraphqlOperation::from_request(&request) {
+            Some(operation) => operation,
+            None => {
+                log::debug!("No GraphQL operation found for {}", event.request.uri);
+                return;
+            }
+        };
+        log::trace!("GraphQL operation: {:?}", operation);
+
+        if config.env.graphql_rewrite_uri {
+            if let Some(name) = &operation.operation_name {
+                event.request.uri = uri_with_operation(&event.request.uri, name);
+            }
+        }
+        event.set_metadata("graphql", operation.to_json());
+    }
+}
+
+fn query_string_request(uri: &str) -> Value {
+    let query = uri
+        .split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default();
+    let mut request = serde_json::Map::new();
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        let value = match key.as_ref() {
+            "extensions" => serde_json::from_str(&value).unwrap_or(Value::Null),
+            _ => Value::String(value.into_owned()),
+        };
+        request.insert(key.into_owned(), value);
+    }
+    Value::Object(request)
+}
+
+// "/graphql?x=1" becomes "/graphql/GetUser?x=1" so Moesif groups requests by operation
+fn uri_with_operation(uri: &str, operation_name: &str) -> String {
+    let (path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    };
+
+    let mut rewritten = format!("{}/{}", path.trim_end_matches('/'), operation_name);
+    if let Some(query) = query {
+        rewritten.push('?');
+        rewritten.push_str(query);
+    }
+    rewritten
+}
+
+// Split a document into names, punctuators and strings. Comments, whitespace and commas
+// aren't significant in GraphQL and are dropped.
+fn tokenize(query: &str) -> Vec<String> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() || c == ',' || c == '\u{feff}' {
+            i += 1;
+        } else if c == '#' {
+            while i < chars.len() && chars[i] != '\n' && chars[i] != '\r' {
+                i += 1;
+            }
+        } else if c == '"' {
+            let start = i;
+            let block = chars[i..].starts_with(&['"', '"', '"']);
+            i += if block { 3 } else { 1 };
+            while i < chars.len() {
+                if chars[i] == '\\' {
+                    i += 2;
+                } else if block && chars[i..].starts_with(&['"', '"', '"']) {
+                    i += 3;
+                    break;
+                } else if !block && chars[i] == '"' {
+                    i += 1;
+                    break;
+                } else {
+                    i += 1;
+                }
+            }
+            tokens.push(chars[start..i.min(chars.len())].iter().collect());
+        } else if c.is_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().collect());
+        } else {
+            tokens.push(c.to_string());
+            i += 1;
+        }
+    }
+
+    tokens
+}
+
+// The same document always normalizes to the same string, however it is formatted
+fn normalize(tokens: &[String]) -> String {
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    let mut normalized = String::new();
+
+    for token in tokens {
+        if is_word(normalized.chars().last()) && is_word(token.chars().next()) {
+            normalized.push(' ');
+        }
+        normalized.push_str(token);
+    }
+    normalized
+}
+
+// The type and name of the operation to execute: the one named by operationName, otherwise
+// the first one in the document
+fn find_operation<'a>(
+    tokens: &'a [String],
+    operation_name: Option<&str>,
+) -> Option<(&'static str, Option<String>)> {
+    let mut operations: Vec<(&'static str, Option<&'a str>)> = Vec::new();
+    let mut brace_depth = 0usize;
+    let mut paren_depth = 0usize;
+    let mut keyword: Option<&'static str> = None;
+    let mut name: Option<&'a str> = None;
+    let mut expect_name = false;
+
+    for token in tokens {
+        match token.as_str() {
+            "(" => paren_depth += 1,
+            ")" => paren_depth = paren_depth.saturating_sub(1),
+            // Object values in variable defaults don't open a selection set
+            "{" if paren_depth == 0 => {
+                if brace_depth == 0 {
+                    match keyword.take() {
+                        Some("fragment") => {}
+                        Some(operation_type) => operations.push((operation_type, name.take())),
+                        // Query shorthand, "{ user { id } }"
+                        None => operations.push(("query", None)),
+                    }
+                }
+                brace_depth += 1;
+            }
+            "}" if paren_depth == 0 => brace_depth = brace_depth.saturating_sub(1),
+            word if brace_depth == 0 && paren_depth == 0 => {
+                let operation_type = match word {
+                    "query" => Some("query"),
+                    "mutation" => Some("mutation"),
+                    "subscription" => Some("subscription"),
+                    "fragment" => Some("fragment"),
+                    _ => None,
+                };
+                match operation_type {
+                    Some(operation_type) if keyword.is_none() => {
+                        keyword = Some(operation_type);
+                        name = None;
+                        expect_name = true;
+                        continue;
+                    }
+                    _ if expect_name
+                        && word.starts_with(|c: char| c.is_alphabetic() || c == '_') =>
+                    {
+                        name = Some(word);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        expect_name = false;
+    }
+
+    let (operation_type, name) = match operation_name {
+        Some(operation_name) => operations
+            .into_iter()
+            .find(|(_, name)| *name == Some(operation_name))?,
+        None => operations.into_iter().next()?,
+    };
+    Some((operation_type, name.map(str::to_string)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graphql_operation_from_request() {
+        // arrange
+        let document = r#"
+            # Fetch a user
+            query GetUser($id: ID!, $filter: Filter = {active: true}) {
+                user(id: $id) { id, name }
+            }
+            mutation UpdateUser($id: ID!) { updateUser(id: $id, name: "a { b") { id } }
+        "#;
+        let reformatted =
+            "query GetUser($id:ID!,$filter:Filter={active:true}){user(id:$id){id name}} \
+            mutation UpdateUser($id:ID!){updateUser(id:$id,name:\"a { b\"){id}}";
+
+        // act
+        let named = GraphqlOperation::from_request(
+            &json!({"query": document, "operationName": "UpdateUser"}),
+        )
+        .unwrap();
+        let first = GraphqlOperation::from_request(&json!({"query": document})).unwrap();
+        let same = GraphqlOperation::from_request(&json!({"query": reformatted})).unwrap();
+        let shorthand = GraphqlOperation::from_request(&json!({"query": "{ me { id } }"})).unwrap();
+
+        // assert
+        assert_eq!(named.operation_name, Some("UpdateUser".to_string()));
+        assert_eq!(named.operation_type, Some("mutation".to_string()));
+        assert_eq!(first.operation_name, Some("GetUser".to_string()));
+        assert_eq!(first.operation_type, Some("query".to_string()));
+        assert_eq!(first.query_hash, same.query_hash);
+        assert_eq!(shorthand.operation_type, Some("query".to_string()));
+        assert_eq!(shorthand.operation_name, None);
+        assert_eq!(
+            uri_with_operation("/graphql?v=1", "GetUser"),
+            "/graphql/GetUser?v=1"
+        );
+    }
+
+    #[test]
+    fn test_graphql_capture_reads_raw_documents() {
+        // arrange
+        let cfg = Config::default();
+        let mut capture = GraphqlCapture::default();
+        let mut event = Event::default();
+        event.request.uri = "/graphql".to_string();
+        event.request.headers.insert(
+            "content-type".to_string(),
+            "application/graphql; charset=utf-8".to_string(),
+        );
+
+        // act
+        capture
+            .buffer
+            .append(b"mutation Login { login { token } }", 1000);
+        capture.apply_to_event(&cfg, &mut event);
+
+        // assert
+        assert_eq!(event.metadata["graphql"]["operation_name"], "Login");
+        assert_eq!(event.metadata["graphql"]["operation_type"], "mutation");
+    }
+}
@@ -0,0 +1,202 @@
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, MethodDescriptor};
+
+use crate::config::Config;
+
+// Load a FileDescriptorSet, e.g. generated with `protoc --include_imports --descriptor_set_out`
+pub fn load_descriptor_pool(path: &str) -> Option<DescriptorPool> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::error!("Failed to read descriptor set {}: {}", path, e);
+            return None;
+        }
+    };
+
+    match DescriptorPool::decode(bytes.as_slice()) {
+        Ok(pool) => {
+            log::info!(
+                "Loaded {} gRPC services from {}",
+                pool.services().len(),
+                path
+            );
+            Some(pool)
+        }
+        Err(e) => {
+            log::error!("Invalid descriptor set {}: {}", path, e);
+            None
+        }
+    }
+}
+
+// Bodies in gRPC framing. grpc-web-text bodies are base64 encoded on top of it and left alone.
+pub fn is_grpc_content_type(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    content_type.starts_with("application/grpc")
+        && !content_type.starts_with("application/grpc-web-text")
+}
+
+// The method called by a gRPC request, from its "/<package>.<Service>/<Method>" path
+pub fn method_for(config: &Config, path: &str) -> Option<MethodDescriptor> {
+    let pool = config.grpc_descriptors.as_ref()?;
+    let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+
+    let method = pool
+        .get_service_by_name(service)?
+        .methods()
+        .find(|m| m.name() == method);
+    if method.is_none() {
+        log::debug!("No descriptor for gRPC method {}", path);
+    }
+    method
+}
+
+// Split a body into its length-prefixed messages. Compressed messages can't be decoded.
+fn split_messages(mut data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut messages = Vec::new();
+
+    while !data.is_empty() {
+        if data.len() < 5 {
+            return None;
+        }
+        let flags = data[0];
+        let length = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+        let message = data.get(5..5 + length)?;
+        data = &data[5 + length..];
+
+        match flags {
+            0 => messages.push(message),
+            // gRPC-Web sends its trailers as a final frame
+            0x80 => {}
+            _ => return None,
+        }
+    }
+
+    Some(messages)
+}
+
+// Decode the messages of a body to JSON: the message itself for unary calls, a list of
+// messages for streams
+pub fn decode_messages(descriptor: &MessageDescriptor, data: &[u8]) -> Option<serde_json::Value> {
+    let messages = split_messages(data)?
+        .into_iter()
+        .map(|message| {
+            let message = DynamicMessage::decode(descriptor.clone(), message)
+                .map_err(|e| log::debug!("Failed to decode {}: {}", descriptor.full_name(), e))
+                .ok()?;
+            serde_json::to_value(&message).ok()
+        })
+        .collect::<Option<Vec<serde_json::Value>>>()?;
+
+    match messages.len() {
+        1 => messages.into_iter().next(),
+        _ => Some(serde_json::Value::Array(messages)),
+    }
+}
+
+// gRPC responses are HTTP 200 whatever the outcome, map the gRPC status to the HTTP status
+// closest in meaning, as https://cloud.google.com/apis/design/errors#handling_errors does
+pub fn grpc_status_to_http(status: u32) -> usize {
+    match status {
+        0 => 200,
+        1 => 499,
+        3 | 9 | 11 => 400,
+        4 => 504,
+        5 => 404,
+        6 | 10 => 409,
+        7 => 403,
+        8 => 429,
+        12 => 501,
+        14 => 503,
+        16 => 401,
+        _ => 500,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EnvConfig;
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+        MethodDescriptorProto, ServiceDescriptorProto,
+    };
+
+    fn field(name: &str, number: i32, field_type: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(field_type as i32),
+            json_name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn shop_descriptors() -> DescriptorPool {
+        let file = FileDescriptorProto {
+            name: Some("shop.proto".to_string()),
+            package: Some("shop".to_string()),
+            syntax: Some("proto3".to_string()),
+            message_type: vec![DescriptorProto {
+                name: Some("Order".to_string()),
+                field: vec![
+                    field("id", 1, Type::String),
+                    field("quantity", 2, Type::Int32),
+                ],
+                ..Default::default()
+            }],
+            service: vec![ServiceDescriptorProto {
+                name: Some("Shop".to_string()),
+                method: vec![MethodDescriptorProto {
+                    name: Some("PlaceOrder".to_string()),
+                    input_type: Some(".shop.Order".to_string()),
+                    output_type: Some(".shop.Order".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let set = FileDescriptorSet { file: vec![file] };
+        DescriptorPool::decode(set.encode_to_vec().as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_decode_messages_for_method() {
+        // arrange
+        let mut cfg = Config::new(EnvConfig::default());
+        cfg.grpc_descriptors = Some(shop_descriptors());
+        // id: "o-1", quantity: 3
+        let message = [0x0a, 0x03, b'o', b'-', b'1', 0x10, 0x03];
+        let mut body = vec![0, 0, 0, 0, message.len() as u8];
+        body.extend_from_slice(&message);
+
+        // act
+        let method = method_for(&cfg, "/shop.Shop/PlaceOrder").unwrap();
+        let unary = decode_messages(&method.input(), &body);
+        let stream = decode_messages(&method.input(), &body.repeat(2));
+        let compressed = decode_messages(&method.input(), &[1, 0, 0, 0, 0]);
+
+        // assert
+        assert!(method_for(&cfg, "/shop.Shop/CancelOrder").is_none());
+        assert_eq!(unary, Some(serde_json::json!({"id": "o-1", "quantity": 3})));
+        assert_eq!(stream.unwrap().as_array().unwrap().len(), 2);
+        assert_eq!(compressed, None);
+        assert_eq!(grpc_status_to_http(5), 404);
+    }
+
+    #[test]
+    fn test_is_grpc_content_type() {
+        // act
+        let grpc = is_grpc_content_type("application/grpc+proto");
+        let grpc_web = is_grpc_content_type("application/grpc-web");
+        let grpc_web_text = is_grpc_content_type("application/grpc-web-text+proto");
+
+        // assert
+        assert!(grpc);
+        assert!(grpc_web);
+        assert!(!grpc_web_text);
+    }
+}
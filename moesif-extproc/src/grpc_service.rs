@@ -1,3 +1,4 @@
+use envoy_ext_proc_proto::envoy::extensions::filters::http::ext_proc::v3::processing_mode::BodySendMode;
 use envoy_ext_proc_proto::envoy::service::ext_proc::v3::{
     external_processor_server::ExternalProcessor, processing_request, ProcessingRequest,
     ProcessingResponse,
@@ -10,16 +11,27 @@ use futures_util::StreamExt;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::Mutex;
+use tokio::time::Instant;
 
+use crate::application_id::resolve_application_id;
+use crate::body::BodyCapture;
 use crate::config::Config;
 use crate::event::Event;
+use crate::graphql::GraphqlCapture;
+use crate::lifecycle::{ExchangeLifecycle, Phase, StreamTimeouts, TerminationReason};
+use crate::llm::LlmUsageCapture;
+use crate::metadata::{add_metadata_identity_to_event, dynamic_metadata_for};
 use crate::root_context::EventRootContext;
+use crate::route_policy::{RouteConfigCache, RoutePolicy};
+use crate::sizes::ExchangeSizes;
+use crate::timing::ExchangeTimings;
 use crate::utils::*;
 
 #[derive(Default)]
 pub struct MoesifGlooExtProcGrpcService {
     config: Arc<Config>, // Store the config in the service
     event_context: Arc<Mutex<EventRootContext>>,
+    route_configs: Arc<RouteConfigCache>,
 }
 
 impl MoesifGlooExtProcGrpcService {
@@ -34,6 +46,7 @@ impl MoesifGlooExtProcGrpcService {
         let service = MoesifGlooExtProcGrpcService {
             config: Arc::new(config),
             event_context: Arc::new(Mutex::new(root_context)),
+            route_configs: Arc::default(),
         };
 
         // Start periodic sending in the background
@@ -82,38 +95,160 @@ impl ExternalProcessor for MoesifGlooExtProcGrpcService {
         log::trace!("Processing new gRPC request...");
         let (tx, rx) = tokio::sync::mpsc::channel(32);
 
-        let config = Arc::clone(&self.config);
-        let mut request_headers_received = false;
+        let global_config = Arc::clone(&self.config);
+        let mut config = Arc::clone(&global_config);
+        let route_configs = Arc::clone(&self.route_configs);
+        let mut skip_event = false;
+        let mut application_id = global_config.env.moesif_application_id.clone();
 
         tokio::spawn({
             let event_context = Arc::clone(&self.event_context);
             async move {
                 let mut event = Event::default(); // Event associated with this channel
+                let mut timings = ExchangeTimings::default();
+                let mut sizes = ExchangeSizes::default();
+                let mut body_capture = BodyCapture::default();
+                let mut llm_usage: Option<LlmUsageCapture> = None;
+                let mut graphql: Option<GraphqlCapture> = None;
+                let mut lifecycle = ExchangeLifecycle::default();
+                let mut termination: Option<TerminationReason> = None;
+                let timeouts = StreamTimeouts::new(&global_config.env, Instant::now());
+
+                loop {
+                    let next_message = request.get_mut().next();
+                    let message = match timeouts.next_deadline(Instant::now()) {
+                        Some((deadline, reason)) => {
+                            match tokio::time::timeout_at(deadline, next_message).await {
+                                Ok(message) => message,
+                                Err(_) => {
+                                    log::warn!(
+                                        "Stream timed out ({}), finalizing the exchange.",
+                                        reason.as_str()
+                                    );
+                                    termination = Some(reason);
+                                    break;
+                                }
+                            }
+                        }
+                        None => next_message.await,
+                    };
 
-                while let Some(message) = request.get_mut().next().await {
                     match message {
-                        Ok(msg) => {
+                        None => break,
+                        Some(Ok(msg)) => {
                             log::trace!("Received message: {:?}", msg);
+                            let now = Utc::now();
 
-                            if let Some(processing_request::Request::RequestHeaders(headers_msg)) =
-                                &msg.request
-                            {
-                                log::trace!("Processing request headers...");
-                                request_headers_received = true;
-                                event.request.time = Utc::now().to_rfc3339();
-                                log::trace!("Generated request time: {}", event.request.time);
+                            match &msg.request {
+                                Some(processing_request::Request::RequestHeaders(headers_msg)) => {
+                                    log::trace!("Processing request headers...");
 
-                                process_request_headers(&config, &mut event, headers_msg).await;
+                                    // Routes can override the global configuration
+                                    let policy = RoutePolicy::from_request(&global_config, &msg);
+                                    skip_event = policy.skip();
+                                    config = route_configs.config_for(&policy, &global_config);
+
+                                    timings.request_headers = Some(now);
+                                    sizes.request.on_headers(headers_msg);
+                                    if headers_msg.end_of_stream {
+                                        timings.request_end = Some(now);
+                                    }
+                                    event.request.time = now.to_rfc3339();
+                                    log::trace!("Generated request time: {}", event.request.time);
+
+                                    process_request_headers(&config, &mut event, headers_msg).await;
+                                    application_id = resolve_application_id(
+                                        &config,
+                                        policy.application_id.as_deref(),
+                                        request_authority(headers_msg).as_deref(),
+                                        &event.request.uri,
+                                    );
+                                    add_metadata_identity_to_event(
+                                        &config,
+                                        &mut event,
+                                        msg.metadata_context.as_ref(),
+                                    );
+                                    if !skip_event {
+                                        body_capture = BodyCapture::for_request(
+                                            &config,
+                                            &event,
+                                            !headers_msg.end_of_stream,
+                                        );
+                                        llm_usage = LlmUsageCapture::for_request(&config, &event);
+                                        graphql = GraphqlCapture::for_request(
+                                            &config,
+                                            &event.request.uri,
+                                        );
+                                    }
+                                }
+                                Some(processing_request::Request::RequestBody(body_msg)) => {
+                                    sizes.request.on_body_chunk(&body_msg.body);
+                                    body_capture.append_request_chunk(&config, &body_msg.body);
+                                    if let Some(graphql) = graphql.as_mut() {
+                                        graphql.append_chunk(&config, &body_msg.body);
+                                    }
+                                    if body_msg.end_of_stream {
+                                        timings.request_end = Some(now);
+                                    }
+                                }
+                                Some(processing_request::Request::RequestTrailers(
+                                    trailers_msg,
+                                )) => {
+                                    timings.request_end = Some(now);
+                                    process_request_trailers(&mut event, trailers_msg);
+                                }
+                                Some(processing_request::Request::ResponseHeaders(
+                                    response_headers_msg,
+                                )) => {
+                                    log::trace!("Processing response headers...");
+                                    timings.response_headers = Some(now);
+                                    sizes.response.on_headers(response_headers_msg);
+                                    if response_headers_msg.end_of_stream {
+                                        timings.response_end = Some(now);
+                                    }
+                                    process_response_headers(&mut event, response_headers_msg)
+                                        .await;
+                                    // Nothing of the response follows a 101
+                                    if event.response.as_ref().is_some_and(|r| r.status == 101) {
+                                        timings.response_end = Some(now);
+                                    }
+                                    body_capture.on_response_headers(&config, &event);
+                                    if let Some(llm_usage) = llm_usage.as_mut() {
+                                        llm_usage.on_response_headers(&event);
+                                    }
+                                }
+                                Some(processing_request::Request::ResponseBody(body_msg)) => {
+                                    sizes.response.on_body_chunk(&body_msg.body);
+                                    body_capture.append_response_chunk(&config, &body_msg.body);
+                                    if let Some(llm_usage) = llm_usage.as_mut() {
+                                        llm_usage.append_chunk(&config, &body_msg.body);
+                                    }
+                                    if body_msg.end_of_stream {
+                                        timings.response_end = Some(now);
+                                    }
+                                }
+                                Some(processing_request::Request::ResponseTrailers(
+                                    trailers_msg,
+                                )) => {
+                                    timings.response_end = Some(now);
+                                    process_response_trailers(&mut event, trailers_msg);
+                                }
+                                _ => {}
                             }
 
-                            if let Some(processing_request::Request::ResponseHeaders(
-                                response_headers_msg,
-                            )) = &msg.request
-                            {
-                                log::trace!("Processing response headers...");
-                                process_response_headers(&mut event, response_headers_msg).await;
+                            process_attributes(&config, &mut event, &msg.attributes);
 
-                                if request_headers_received {
+                            // Store the event once the whole response went through
+                            let completed = msg
+                                .request
+                                .as_ref()
+                                .is_some_and(|request| lifecycle.on_phase(Phase::of(request)));
+                            // After a 101 the connection carries another protocol, Envoy sends
+                            // nothing more unless the response body is streamed
+                            let upgraded = completed
+                                && event.response.as_ref().is_some_and(|r| r.status == 101);
+                            if completed && !skip_event {
+                                if lifecycle.request_seen() {
                                     log::trace!(
                                         "Storing event after matching request and response."
                                     );
@@ -122,33 +257,118 @@ impl ExternalProcessor for MoesifGlooExtProcGrpcService {
                                         "Received response without a corresponding request. Storing unmatched response."
                                     );
                                 }
-                                store_and_flush_event(&event_context, &event).await;
+                                finalize_event(
+                                    &config,
+                                    &mut event,
+                                    &timings,
+                                    &sizes,
+                                    &mut body_capture,
+                                    llm_usage.as_mut(),
+                                    graphql.as_ref(),
+                                );
+                                store_and_flush_event(&event_context, &application_id, &event)
+                                    .await;
+                            }
+
+                            // In observability mode Envoy doesn't wait for, or read, responses
+                            if config.env.observability_mode {
+                                if upgraded {
+                                    break;
+                                }
+                                continue;
                             }
 
-                            log::trace!("Sending simplified gRPC response with no headers");
-                            send_grpc_response(tx.clone(), simplified_grpc_response()).await;
+                            match &msg.request {
+                                Some(request) => {
+                                    let mut response = grpc_response_for(request);
+                                    // Only stream the bodies this exchange actually captures
+                                    if let processing_request::Request::RequestHeaders(
+                                        headers_msg,
+                                    ) = request
+                                    {
+                                        let mut mode = body_capture.mode_override(&config);
+                                        // Token usage is read from the response body
+                                        if llm_usage.is_some() {
+                                            mode.response_body_mode = BodySendMode::Streamed as i32;
+                                        }
+                                        // The GraphQL operation is read from the request body,
+                                        // GET requests carry it in the query string
+                                        if graphql.is_some() && !headers_msg.end_of_stream {
+                                            mode.request_body_mode = BodySendMode::Streamed as i32;
+                                        }
+                                        response.mode_override = Some(mode);
+                                        // The identity is known once the request headers are in
+                                        if config.env.emit_dynamic_metadata {
+                                            response.dynamic_metadata =
+                                                Some(dynamic_metadata_for(&event, !skip_event));
+                                        }
+                                    }
+
+                                    log::trace!("Sending pass-through gRPC response");
+                                    send_grpc_response(tx.clone(), response).await;
+                                }
+                                None => log::warn!("Received message without a request phase"),
+                            }
+                            if upgraded {
+                                log::trace!("Switched protocols, ending the stream.");
+                                break;
+                            }
                         }
 
-                        Err(e) => {
+                        Some(Err(e)) => {
                             log::error!("Error receiving message: {:?}", e);
+                            termination = Some(TerminationReason::for_status(&e));
                             if tx
                                 .send(Err(Status::internal("Error processing request")))
                                 .await
                                 .is_err()
                             {
                                 log::error!("Error sending internal error response: {:?}", e);
-                                break;
                             }
+                            // Envoy doesn't continue a failed stream
+                            break;
                         }
                     }
                 }
 
                 // Final processing when the gRPC stream closes
-                if !request_headers_received {
-                    log::warn!(
-                        "Channel closed before receiving a matching request/response. Storing unmatched event."
-                    );
-                    store_and_flush_event(&event_context, &event).await;
+                let closed = match termination {
+                    Some(reason) => lifecycle.on_termination(reason),
+                    None => lifecycle.on_close(),
+                };
+                match closed {
+                    Some(_) if skip_event => {
+                        log::trace!("Route policy skips this exchange, not storing event.");
+                    }
+                    Some(state) => {
+                        match lifecycle.termination_reason() {
+                            Some(reason) => {
+                                log::debug!(
+                                    "Exchange ended without a complete response ({:?}). Storing event with termination reason {}.",
+                                    state,
+                                    reason.as_str()
+                                );
+                                reason.apply_to_event(&mut event);
+                            }
+                            // Envoy wasn't asked to send the response body or trailers, the end
+                            // of the stream is the closest we get to the end of the response
+                            None => {
+                                log::trace!("Channel closed after response headers. Storing event.")
+                            }
+                        }
+                        timings.response_end = Some(Utc::now());
+                        finalize_event(
+                            &config,
+                            &mut event,
+                            &timings,
+                            &sizes,
+                            &mut body_capture,
+                            llm_usage.as_mut(),
+                            graphql.as_ref(),
+                        );
+                        store_and_flush_event(&event_context, &application_id, &event).await;
+                    }
+                    None => {}
                 }
                 log::trace!("Stream processing complete.");
             }
@@ -158,3 +378,485 @@ impl ExternalProcessor for MoesifGlooExtProcGrpcService {
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 }
+
+// Add everything captured along the exchange to its event
+fn finalize_event(
+    config: &Config,
+    event: &mut Event,
+    timings: &ExchangeTimings,
+    sizes: &ExchangeSizes,
+    body_capture: &mut BodyCapture,
+    llm_usage: Option<&mut LlmUsageCapture>,
+    graphql: Option<&GraphqlCapture>,
+) {
+    body_capture.apply_to_event(config, event);
+    if let Some(llm_usage) = llm_usage {
+        llm_usage.apply_to_event(config, event);
+    }
+    if let Some(graphql) = graphql {
+        graphql.apply_to_event(config, event);
+    }
+    finalize_event_timings(event, timings);
+    sizes.apply_to_event(event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EnvConfig;
+    use bytes::Bytes;
+    use envoy_ext_proc_proto::envoy::config::core::v3::{HeaderMap, HeaderValue};
+    use envoy_ext_proc_proto::envoy::extensions::filters::http::ext_proc::v3::processing_mode::HeaderSendMode;
+    use envoy_ext_proc_proto::envoy::service::ext_proc::v3::{
+        external_processor_client::ExternalProcessorClient,
+        external_processor_server::ExternalProcessorServer, processing_response, HttpBody,
+        HttpHeaders, HttpTrailers,
+    };
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Channel, Server};
+
+    fn test_config() -> Config {
+        Config::new(EnvConfig {
+            batch_max_size: 100,
+            batch_max_wait: 60000,
+            ..Default::default()
+        })
+    }
+
+    fn headers(pairs: &[(&str, &str)], end_of_stream: bool) -> HttpHeaders {
+        HttpHeaders {
+            headers: Some(HeaderMap {
+                headers: pairs
+                    .iter()
+                    .map(|(key, value)| HeaderValue {
+                        key: key.to_string(),
+                        raw_value: Bytes::from(value.to_string()),
+                        ..Default::default()
+                    })
+                    .collect(),
+            }),
+            end_of_stream,
+            ..Default::default()
+        }
+    }
+
+    fn message(request: processing_request::Request) -> ProcessingRequest {
+        ProcessingRequest {
+            request: Some(request),
+            ..Default::default()
+        }
+    }
+
+    // Every phase of an HTTP exchange, in the order Envoy sends them
+    fn full_exchange() -> Vec<ProcessingRequest> {
+        vec![
+            message(processing_request::Request::RequestHeaders(headers(
+                &[(":method", "POST"), (":path", "/orders")],
+                false,
+            ))),
+            message(processing_request::Request::RequestBody(HttpBody {
+                body: Bytes::from("{\"id\":1}"),
+                end_of_stream: false,
+            })),
+            message(processing_request::Request::RequestTrailers(
+                HttpTrailers::default(),
+            )),
+            message(processing_request::Request::ResponseHeaders(headers(
+                &[(":status", "200")],
+                false,
+            ))),
+            message(processing_request::Request::ResponseBody(HttpBody {
+                body: Bytes::from("{}"),
+                end_of_stream: false,
+            })),
+            message(processing_request::Request::ResponseTrailers(
+                HttpTrailers::default(),
+            )),
+        ]
+    }
+
+    // Run the messages through a real gRPC server and collect everything it answers
+    async fn exchange(config: Config, messages: Vec<ProcessingRequest>) -> Vec<ProcessingResponse> {
+        exchange_and_store(config, messages).await.0
+    }
+
+    // Same as exchange, along with the events stored for Moesif
+    async fn exchange_and_store(
+        config: Config,
+        messages: Vec<ProcessingRequest>,
+    ) -> (Vec<ProcessingResponse>, Vec<serde_json::Value>) {
+        let (mut client, event_context) = serve(config).await;
+        let mut responses = client
+            .process(tokio_stream::iter(messages))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let mut received = Vec::new();
+        while let Some(response) = responses.message().await.unwrap() {
+            received.push(response);
+        }
+        // The response stream ends once the processing task is done with the exchange
+        let events = event_context.lock().await.queued_events().await;
+        (received, events)
+    }
+
+    // Serve a new service on a local port, returning a client for it and its event queue
+    async fn serve(
+        config: Config,
+    ) -> (
+        ExternalProcessorClient<Channel>,
+        Arc<Mutex<EventRootContext>>,
+    ) {
+        let mut root_context = EventRootContext::new(config.clone());
+        root_context.skip_initial_flush();
+        // Built without the periodic sender, events stay queued
+        let service = MoesifGlooExtProcGrpcService {
+            config: Arc::new(config),
+            event_context: Arc::new(Mutex::new(root_context)),
+            route_configs: Arc::default(),
+        };
+        let event_context = Arc::clone(&service.event_context);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(ExternalProcessorServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let client = ExternalProcessorClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        (client, event_context)
+    }
+
+    fn phase(response: &ProcessingResponse) -> &'static str {
+        match response.response {
+            Some(processing_response::Response::RequestHeaders(_)) => "request_headers",
+            Some(processing_response::Response::ResponseHeaders(_)) => "response_headers",
+            Some(processing_response::Response::RequestBody(_)) => "request_body",
+            Some(processing_response::Response::ResponseBody(_)) => "response_body",
+            Some(processing_response::Response::RequestTrailers(_)) => "request_trailers",
+            Some(processing_response::Response::ResponseTrailers(_)) => "response_trailers",
+            Some(processing_response::Response::ImmediateResponse(_)) => "immediate_response",
+            None => "none",
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_sends_no_responses_in_observability_mode() {
+        // arrange
+        let mut config = test_config();
+        config.env.observability_mode = true;
+
+        // act
+        let (responses, events) = exchange_and_store(config, full_exchange()).await;
+
+        // assert
+        assert!(responses.is_empty());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["request"]["verb"], "POST");
+        assert_eq!(events[0]["request"]["uri"], "/orders");
+        assert_eq!(events[0]["response"]["status"], 200);
+    }
+
+    #[tokio::test]
+    async fn test_process_returns_identity_as_dynamic_metadata() {
+        // arrange
+        let mut config = test_config();
+        config.env.emit_dynamic_metadata = true;
+        config.env.user_id_header = Some("x-user-id".to_string());
+        let messages = vec![message(processing_request::Request::RequestHeaders(
+            headers(&[(":path", "/orders"), ("x-user-id", "user-1")], true),
+        ))];
+
+        // act
+        let responses = exchange(config, messages).await;
+
+        // assert
+        let dynamic_metadata = responses[0].dynamic_metadata.as_ref().unwrap();
+        let moesif = crate::metadata::struct_to_json(dynamic_metadata);
+        assert_eq!(
+            moesif,
+            serde_json::json!({"moesif": {"user_id": "user-1", "logged": true}})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_stores_local_reply_before_request_body() {
+        // arrange
+        let messages = vec![
+            message(processing_request::Request::RequestHeaders(headers(
+                &[(":method", "POST"), (":path", "/orders")],
+                false,
+            ))),
+            message(processing_request::Request::ResponseHeaders(headers(
+                &[(":status", "403")],
+                true,
+            ))),
+            message(processing_request::Request::RequestBody(HttpBody {
+                body: Bytes::from("{}"),
+                end_of_stream: true,
+            })),
+        ];
+
+        // act
+        let (responses, events) = exchange_and_store(test_config(), messages).await;
+
+        // assert
+        assert_eq!(responses.len(), 3);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["response"]["status"], 403);
+    }
+
+    #[tokio::test]
+    async fn test_process_stores_once_with_trailers_after_end_of_stream() {
+        // arrange
+        let messages = vec![
+            message(processing_request::Request::RequestHeaders(headers(
+                &[(":method", "GET"), (":path", "/orders")],
+                true,
+            ))),
+            message(processing_request::Request::ResponseHeaders(headers(
+                &[(":status", "200")],
+                false,
+            ))),
+            message(processing_request::Request::ResponseBody(HttpBody {
+                body: Bytes::from("{}"),
+                end_of_stream: true,
+            })),
+            message(processing_request::Request::ResponseTrailers(
+                HttpTrailers::default(),
+            )),
+        ];
+
+        // act
+        let (responses, events) = exchange_and_store(test_config(), messages).await;
+
+        // assert
+        assert_eq!(responses.len(), 4);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["response"]["status"], 200);
+    }
+
+    #[tokio::test]
+    async fn test_process_stores_on_close_after_response_headers() {
+        // arrange
+        let messages = vec![
+            message(processing_request::Request::RequestHeaders(headers(
+                &[(":method", "GET"), (":path", "/orders")],
+                true,
+            ))),
+            message(processing_request::Request::ResponseHeaders(headers(
+                &[(":status", "200")],
+                false,
+            ))),
+        ];
+
+        // act
+        let (_, events) = exchange_and_store(test_config(), messages).await;
+
+        // assert
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["response"]["status"], 200);
+        assert!(events[0]["metadata"].get("termination_reason").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_process_stores_on_close_during_response_body() {
+        // arrange
+        // A streamed gRPC response whose trailers aren't sent to the processor
+        let messages = vec![
+            message(processing_request::Request::RequestHeaders(headers(
+                &[(":method", "POST"), (":path", "/orders.Orders/List")],
+                false,
+            ))),
+            message(processing_request::Request::RequestBody(HttpBody {
+                body: Bytes::from_static(b"\0\0\0\0\0"),
+                end_of_stream: true,
+            })),
+            message(processing_request::Request::ResponseHeaders(headers(
+                &[(":status", "200"), ("content-type", "application/grpc")],
+                false,
+            ))),
+            message(processing_request::Request::ResponseBody(HttpBody {
+                body: Bytes::from_static(b"\0\0\0\0\0"),
+                end_of_stream: false,
+            })),
+            message(processing_request::Request::ResponseBody(HttpBody {
+                body: Bytes::from_static(b"\0\0\0\0\0"),
+                end_of_stream: false,
+            })),
+        ];
+
+        // act
+        let (_, events) = exchange_and_store(test_config(), messages).await;
+
+        // assert
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["response"]["status"], 200);
+        assert!(events[0]["metadata"].get("termination_reason").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_process_times_out_stuck_streams() {
+        // arrange
+        let mut config = test_config();
+        config.env.stream_idle_timeout = 600000;
+        let (mut client, event_context) = serve(config).await;
+        // Envoy keeps the stream open but the request body never ends
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tx.send(message(processing_request::Request::RequestHeaders(
+            headers(&[(":method", "POST"), (":path", "/upload")], false),
+        )))
+        .await
+        .unwrap();
+        tx.send(message(processing_request::Request::RequestBody(
+            HttpBody {
+                body: Bytes::from("{"),
+                end_of_stream: false,
+            },
+        )))
+        .await
+        .unwrap();
+        let mut responses = client
+            .process(ReceiverStream::new(rx))
+            .await
+            .unwrap()
+            .into_inner();
+        for _ in 0..2 {
+            responses.message().await.unwrap().unwrap();
+        }
+
+        // act
+        tokio::time::pause();
+        let after_timeout = responses.message().await.unwrap();
+
+        // assert
+        let events = event_context.lock().await.queued_events().await;
+        assert!(after_timeout.is_none());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["response"]["status"], 504);
+        assert_eq!(events[0]["metadata"]["incomplete"], true);
+        assert_eq!(events[0]["metadata"]["termination_reason"], "idle_timeout");
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn test_process_ends_websockets_on_upgrade() {
+        // arrange
+        let (mut client, event_context) = serve(test_config()).await;
+        // Envoy keeps the stream open but sends nothing after the 101
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tx.send(message(processing_request::Request::RequestHeaders(
+            headers(
+                &[
+                    (":method", "GET"),
+                    (":path", "/chat"),
+                    ("upgrade", "websocket"),
+                ],
+                true,
+            ),
+        )))
+        .await
+        .unwrap();
+        tx.send(message(processing_request::Request::ResponseHeaders(
+            headers(&[(":status", "101")], false),
+        )))
+        .await
+        .unwrap();
+
+        // act
+        let mut responses = client
+            .process(ReceiverStream::new(rx))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut received = Vec::new();
+        while let Some(response) = responses.message().await.unwrap() {
+            received.push(response);
+        }
+
+        // assert
+        let events = event_context.lock().await.queued_events().await;
+        assert_eq!(received.len(), 2);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["response"]["status"], 101);
+        assert!(events[0]["metadata"].get("termination_reason").is_none());
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn test_process_carries_trailer_modes_into_mode_override() {
+        // arrange
+        let mut env = test_config().env;
+        env.request_trailer_mode = "skip".to_string();
+        env.response_trailer_mode = "SEND".to_string();
+        let messages = vec![message(processing_request::Request::RequestHeaders(
+            headers(&[(":path", "/orders")], true),
+        ))];
+
+        // act
+        let responses = exchange(Config::new(env), messages).await;
+
+        // assert
+        let mode = responses[0].mode_override.as_ref().unwrap();
+        assert_eq!(mode.request_trailer_mode, HeaderSendMode::Skip as i32);
+        assert_eq!(mode.response_trailer_mode, HeaderSendMode::Send as i32);
+    }
+
+    #[tokio::test]
+    async fn test_process_streams_graphql_request_bodies_only_when_sent() {
+        // arrange
+        let mut config = test_config();
+        config.graphql_paths = vec![regex::Regex::new("^/graphql").unwrap()];
+        let request = |method: &str, end_of_stream: bool| {
+            vec![message(processing_request::Request::RequestHeaders(
+                headers(
+                    &[(":method", method), (":path", "/graphql?query={me}")],
+                    end_of_stream,
+                ),
+            ))]
+        };
+
+        // act
+        let get = exchange(config.clone(), request("GET", true)).await;
+        let post = exchange(config, request("POST", false)).await;
+
+        // assert
+        let body_mode = |responses: &[ProcessingResponse]| {
+            responses[0]
+                .mode_override
+                .as_ref()
+                .unwrap()
+                .request_body_mode
+        };
+        assert_eq!(body_mode(&get), BodySendMode::None as i32);
+        assert_eq!(body_mode(&post), BodySendMode::Streamed as i32);
+    }
+
+    #[tokio::test]
+    async fn test_process_answers_each_phase_with_matching_response() {
+        // arrange
+        let messages = full_exchange();
+
+        // act
+        let responses = exchange(test_config(), messages).await;
+
+        // assert
+        let phases: Vec<&str> = responses.iter().map(phase).collect();
+        assert_eq!(
+            phases,
+            vec![
+                "request_headers",
+                "request_body",
+                "request_trailers",
+                "response_headers",
+                "response_body",
+                "response_trailers",
+            ]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use regex::Regex;
+use std::collections::HashMap;
+
+use crate::utils::get_cookie_value;
+
+const SOURCE_KINDS: [&str; 4] = ["header:", "query:", "cookie:", "path:"];
+
+// A place in the request a user or company id can be read from
+#[derive(Clone, Debug)]
+pub enum IdSource {
+    Header(String),
+    Query(String),
+    Cookie(String),
+    Path(Regex),
+}
+
+impl IdSource {
+    fn parse(source: &str) -> Option<IdSource> {
+        let (kind, value) = source.split_once(':')?;
+        if value.is_empty() {
+            return None;
+        }
+
+        match kind {
+            "header" => Some(IdSource::Header(value.to_lowercase())),
+            "query" => Some(IdSource::Query(value.to_string())),
+            "cookie" => Some(IdSource::Cookie(value.to_string())),
+            "path" => match Regex::new(value) {
+                Ok(regex) if regex.captures_len() > 1 => Some(IdSource::Path(regex)),
+                Ok(_) => {
+                    log::error!("Path id source {:?} has no capture group", value);
+                    None
+                }
+                Err(e) => {
+                    log::error!("Invalid path id source {:?}: {}", value, e);
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    pub fn resolve(&self, headers: &HashMap<String, String>, uri: &str) -> Option<String> {
+        let value = match self {
+            IdSource::Header(name) => headers.get(name).cloned(),
+            IdSource::Cookie(name) => get_cookie_value(headers, name),
+            IdSource::Query(name) => {
+                let (_, query) = uri.split_once('?')?;
+                url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.into_owned())
+            }
+            IdSource::Path(regex) => {
+                // Use the first capture group that participated in the match
+                let captures = regex.captures(uri)?;
+                captures
+                    .iter()
+                    .skip(1)
+                    .flatten()
+                    .next()
+                    .map(|m| m.as_str().to_string())
+            }
+        };
+
+        value.filter(|v| !v.is_empty())
+    }
+}
+
+// Parse a comma separated list such as "query:api_key,cookie:session,path:/v1/orgs/([^/]+)/".
+// Commas inside a path regex are kept as long as they aren't followed by a source kind.
+pub fn parse_id_sources(spec: &str) -> Vec<IdSource> {
+    let mut raw_sources: Vec<String> = Vec::new();
+    for part in spec.split(',') {
+        let trimmed = part.trim_start();
+        match raw_sources.last_mut() {
+            Some(last) if !SOURCE_KINDS.iter().any(|kind| trimmed.starts_with(kind)) => {
+                last.push(',');
+                last.push_str(part);
+            }
+            _ => raw_sources.push(trimmed.to_string()),
+        }
+    }
+
+    raw_sources
+        .iter()
+        .map(|source| source.trim())
+        .filter(|source| !source.is_empty())
+        .filter_map(|source| {
+            let parsed = IdSource::parse(source);
+            if parsed.is_none() {
+                log::error!("Ignoring invalid id source {:?}", source);
+            }
+            parsed
+        })
+        .collect()
+}
+
+// First match wins
+pub fn resolve_first(
+    sources: &[IdSource],
+    headers: &HashMap<String, String>,
+    uri: &str,
+) -> Option<String> {
+    sources
+        .iter()
+        .find_map(|source| source.resolve(headers, uri))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_first_match_wins() {
+        // arrange
+        let sources = parse_id_sources(
+            "header:X-Tenant, query:api_key,cookie:session,path:/v1/orgs/(?P<company>[^/]{1,64})/",
+        );
+        let headers = HashMap::from([("cookie".to_string(), "a=b; session=s-1".to_string())]);
+
+        // act
+        let from_query = resolve_first(&sources, &headers, "/v1/orgs/acme/users?api_key=k%201");
+        let from_cookie = resolve_first(&sources, &headers, "/v1/orgs/acme/users");
+        let from_path = resolve_first(&sources[3..], &headers, "/v1/orgs/acme/users");
+
+        // assert
+        assert_eq!(sources.len(), 4);
+        assert_eq!(from_query, Some("k 1".to_string()));
+        assert_eq!(from_cookie, Some("s-1".to_string()));
+        assert_eq!(from_path, Some("acme".to_string()));
+    }
+}
@@ -0,0 +1,255 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::config::Config;
+use crate::utils::get_cookie_value;
+
+// Load a JWKS document from disk, used to verify token signatures
+pub fn load_jwks(path: &str) -> Option<JwkSet> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            log::error!("Failed to read JWKS file {}: {}", path, e);
+            return None;
+        }
+    };
+
+    match serde_json::from_str::<JwkSet>(&contents) {
+        Ok(jwks) => {
+            log::info!("Loaded {} keys from JWKS file {}", jwks.keys.len(), path);
+            Some(jwks)
+        }
+        Err(e) => {
+            log::error!("Failed to parse JWKS file {}: {}", path, e);
+            None
+        }
+    }
+}
+
+// Find the token in the configured cookie or authorization header and decode its claims
+pub fn extract_claims(config: &Config, headers: &HashMap<String, String>) -> Option<Value> {
+    let token = extract_token(config, headers)?;
+    log::trace!("Found JWT in request");
+
+    if config.env.jwks_path.is_some() {
+        verify_claims(config, &token)
+    } else {
+        decode_unverified_claims(&token)
+    }
+}
+
+fn extract_token(config: &Config, headers: &HashMap<String, String>) -> Option<String> {
+    if let Some(cookie_name) = &config.env.authorization_cookie_name {
+        if let Some(token) = get_cookie_value(headers, cookie_name) {
+            return Some(token);
+        }
+    }
+
+    let header_name = config.env.authorization_header_name.to_lowercase();
+    let value = headers.get(&header_name)?.trim();
+
+    // Accept both "Bearer <token>" and a bare token
+    let token = match value.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+        _ => value,
+    };
+
+    if token.is_empty() {
+        None
+    } else {
+        Some(token.to_string())
+    }
+}
+
+fn verify_claims(config: &Config, token: &str) -> Option<Value> {
+    // Fail closed: when a JWKS path is configured, unverifiable tokens are ignored
+    let jwks = match &config.jwks {
+        Some(jwks) => jwks,
+        None => {
+            log::warn!("JWKS_PATH is set but no keys are loaded, ignoring JWT claims");
+            return None;
+        }
+    };
+
+    let header = decode_header(token)
+        .map_err(|e| log::debug!("Invalid JWT header: {}", e))
+        .ok()?;
+
+    // Without a kid every key is a candidate, the token must verify against one of them
+    let candidates: Vec<&Jwk> = match &header.kid {
+        Some(kid) => jwks.find(kid).into_iter().collect(),
+        None => jwks.keys.iter().collect(),
+    };
+    let claims = candidates
+        .into_iter()
+        .filter(|jwk| key_allows(jwk, header.alg))
+        .find_map(|jwk| verify_with_key(jwk, token, header.alg));
+    if claims.is_none() {
+        log::debug!("No JWKS key verifies the JWT, kid {:?}", header.kid);
+    }
+    claims
+}
+
+// A key that names its algorithm is only used with it, the token header can't pick another one
+fn key_allows(jwk: &Jwk, alg: Algorithm) -> bool {
+    match jwk.common.key_algorithm {
+        Some(key_algorithm) => key_algorithm.to_string().parse::<Algorithm>().ok() == Some(alg),
+        None => true,
+    }
+}
+
+fn verify_with_key(jwk: &Jwk, token: &str, alg: Algorithm) -> Option<Value> {
+    let key = DecodingKey::from_jwk(jwk)
+        .map_err(|e| log::warn!("Unusable JWKS key {:?}: {}", jwk.common.key_id, e))
+        .ok()?;
+
+    // Only the signature and the time based claims are checked, audience and issuer
+    // are left to the gateway's own authentication
+    let mut validation = Validation::new(alg);
+    validation.required_spec_claims.clear();
+    validation.validate_aud = false;
+
+    decode::<Value>(token, &key, &validation)
+        .map(|data| data.claims)
+        .map_err(|e| log::debug!("JWT verification failed: {}", e))
+        .ok()
+}
+
+fn decode_unverified_claims(token: &str) -> Option<Value> {
+    let payload = token.split('.').nth(1)?;
+    let bytes = URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .map_err(|e| log::debug!("Invalid JWT payload encoding: {}", e))
+        .ok()?;
+
+    serde_json::from_slice(&bytes)
+        .map_err(|e| log::debug!("Invalid JWT payload: {}", e))
+        .ok()
+}
+
+// Resolve a claim by name or dot separated path (e.g. "sub" or "org.id")
+pub fn claim_as_string(claims: &Value, path: &str) -> Option<String> {
+    // Namespaced claims such as "https://example.com/org" contain dots themselves
+    let claim = match claims.get(path) {
+        Some(claim) => claim,
+        None => path
+            .split('.')
+            .try_fold(claims, |current, segment| current.get(segment))?,
+    };
+
+    match claim {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EnvConfig;
+    use serde_json::json;
+
+    fn unsigned_token(claims: &Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap());
+        format!("{}.{}.signature", header, payload)
+    }
+
+    #[test]
+    fn test_extract_claims_from_bearer_header() {
+        // arrange
+        let token = unsigned_token(&json!({"sub": "user-1", "org": {"id": 42}}));
+        let cfg = Config::new(EnvConfig {
+            authorization_header_name: "Authorization".to_string(),
+            ..Default::default()
+        });
+        let headers = HashMap::from([("authorization".to_string(), format!("Bearer {}", token))]);
+
+        // act
+        let claims = extract_claims(&cfg, &headers).unwrap();
+
+        // assert
+        assert_eq!(claim_as_string(&claims, "sub"), Some("user-1".to_string()));
+        assert_eq!(claim_as_string(&claims, "org.id"), Some("42".to_string()));
+        assert_eq!(claim_as_string(&claims, "org.name"), None);
+    }
+
+    // A config verifying tokens against a JWKS file holding these keys
+    fn jwks_config(name: &str, keys: Value) -> Config {
+        let path = std::env::temp_dir().join(format!("moesif-jwks-{}.json", name));
+        std::fs::write(&path, json!({ "keys": keys }).to_string()).unwrap();
+        Config::new(EnvConfig {
+            authorization_header_name: "authorization".to_string(),
+            jwks_path: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        })
+    }
+
+    fn signed_token(kid: Option<&str>, alg: Algorithm, secret: &[u8]) -> HashMap<String, String> {
+        let mut header = jsonwebtoken::Header::new(alg);
+        header.kid = kid.map(str::to_string);
+        let token = jsonwebtoken::encode(
+            &header,
+            &json!({"sub": "user-1"}),
+            &jsonwebtoken::EncodingKey::from_secret(secret),
+        )
+        .unwrap();
+        HashMap::from([("authorization".to_string(), format!("Bearer {}", token))])
+    }
+
+    #[test]
+    fn test_extract_claims_verifies_against_jwks() {
+        // arrange
+        let oct = |kid: &str, alg: &str, secret: &[u8]| json!({"kty": "oct", "kid": kid, "alg": alg, "k": URL_SAFE_NO_PAD.encode(secret)});
+        let cfg = jwks_config(
+            "verify",
+            json!([
+                oct("other", "HS256", b"other-secret"),
+                oct("main", "HS256", b"main-secret"),
+                oct("strict", "HS384", b"strict-secret"),
+            ]),
+        );
+
+        // act
+        let with_kid = extract_claims(
+            &cfg,
+            &signed_token(Some("main"), Algorithm::HS256, b"main-secret"),
+        );
+        let without_kid =
+            extract_claims(&cfg, &signed_token(None, Algorithm::HS256, b"main-secret"));
+        let wrong_secret = extract_claims(&cfg, &signed_token(None, Algorithm::HS256, b"guess"));
+        let wrong_alg = extract_claims(
+            &cfg,
+            &signed_token(Some("strict"), Algorithm::HS256, b"strict-secret"),
+        );
+
+        // assert
+        assert_eq!(with_kid, Some(json!({"sub": "user-1"})));
+        assert_eq!(without_kid, Some(json!({"sub": "user-1"})));
+        assert_eq!(wrong_secret, None);
+        assert_eq!(wrong_alg, None);
+    }
+
+    #[test]
+    fn test_extract_claims_requires_verification_when_jwks_configured() {
+        // arrange
+        let token = unsigned_token(&json!({"sub": "user-1"}));
+        let cfg = Config::new(EnvConfig {
+            authorization_header_name: "authorization".to_string(),
+            jwks_path: Some("/nonexistent/jwks.json".to_string()),
+            ..Default::default()
+        });
+        let headers = HashMap::from([("authorization".to_string(), token)]);
+
+        // act
+        let claims = extract_claims(&cfg, &headers);
+
+        // assert
+        assert!(claims.is_none());
+    }
+}
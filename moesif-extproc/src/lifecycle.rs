@@ -0,0 +1,637 @@
+use envoy_ext_proc_proto::envoy::service::ext_proc::v3::{processing_request, HttpHeaders};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::{Code, Status};
+
+use crate::config::EnvConfig;
+use crate::event::{Event, ResponseInfo};
+use crate::utils::header_value;
+
+// The ext_proc message received for an HTTP exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    RequestHeaders { end_of_stream: bool },
+    RequestBody { end_of_stream: bool },
+    RequestTrailers,
+    ResponseHeaders { end_of_stream: bool },
+    ResponseBody { end_of_stream: bool },
+    ResponseTrailers,
+}
+
+impl Phase {
+    pub fn of(request: &processing_request::Request) -> Self {
+        match request {
+            processing_request::Request::RequestHeaders(headers) => Phase::RequestHeaders {
+                end_of_stream: headers.end_of_stream,
+            },
+            processing_request::Request::RequestBody(body) => Phase::RequestBody {
+                end_of_stream: body.end_of_stream,
+            },
+            processing_request::Request::RequestTrailers(_) => Phase::RequestTrailers,
+            // A 101 response has no body, the connection switches to another protocol
+            processing_request::Request::ResponseHeaders(headers) => Phase::ResponseHeaders {
+                end_of_stream: headers.end_of_stream || is_switching_protocols(headers),
+            },
+            processing_request::Request::ResponseBody(body) => Phase::ResponseBody {
+                end_of_stream: body.end_of_stream,
+            },
+            processing_request::Request::ResponseTrailers(_) => Phase::ResponseTrailers,
+        }
+    }
+}
+
+fn is_switching_protocols(headers: &HttpHeaders) -> bool {
+    headers
+        .headers
+        .iter()
+        .flat_map(|header_map| header_map.headers.iter())
+        .any(|header| header.key == ":status" && header_value(header) == "101")
+}
+
+// Where an HTTP exchange is at. Each ext_proc stream carries a single exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExchangeState {
+    #[default]
+    Start,
+    RequestHeaders,
+    RequestBody,
+    // The request went through, waiting on the upstream
+    RequestComplete,
+    ResponseHeaders,
+    ResponseBody,
+    Complete,
+    // The stream ended before the response started
+    Aborted,
+}
+
+// Why an exchange ended without a complete response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminationReason {
+    // Envoy closed or cancelled the stream before the response, the client went away
+    ClientDisconnect,
+    // The stream hit its gRPC deadline
+    GatewayTimeout,
+    // Any other stream failure
+    StreamError,
+    // No message arrived within stream_idle_timeout
+    IdleTimeout,
+    // The stream outlived stream_max_duration
+    MaxDuration,
+}
+
+impl TerminationReason {
+    pub fn for_status(status: &Status) -> Self {
+        match status.code() {
+            Code::Cancelled => TerminationReason::ClientDisconnect,
+            Code::DeadlineExceeded => TerminationReason::GatewayTimeout,
+            _ => TerminationReason::StreamError,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TerminationReason::ClientDisconnect => "client_disconnect",
+            TerminationReason::GatewayTimeout => "gateway_timeout",
+            TerminationReason::StreamError => "stream_error",
+            TerminationReason::IdleTimeout => "idle_timeout",
+            TerminationReason::MaxDuration => "max_duration",
+        }
+    }
+
+    // Status recorded when no response was received, nginx's 499 for a client that went away
+    pub fn synthetic_status(&self) -> usize {
+        match self {
+            TerminationReason::ClientDisconnect => 499,
+            TerminationReason::GatewayTimeout => 504,
+            TerminationReason::StreamError => 502,
+            TerminationReason::IdleTimeout | TerminationReason::MaxDuration => 504,
+        }
+    }
+
+    // The processor gave up on the stream, the exchange may still be going on in Envoy
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            TerminationReason::IdleTimeout | TerminationReason::MaxDuration
+        )
+    }
+
+    // A response received before the termination keeps its status
+    pub fn apply_to_event(&self, event: &mut Event) {
+        event.set_metadata("termination_reason", Value::from(self.as_str()));
+        if self.is_timeout() {
+            event.set_metadata("incomplete", Value::Bool(true));
+        }
+        if event.response.is_none() {
+            event.response = Some(ResponseInfo {
+                status: self.synthetic_status(),
+                ..Default::default()
+            });
+        }
+    }
+}
+
+// Bounds the lifetime of a stream so long-lived ones, such as WebSockets or gRPC streaming
+// calls, don't hold their exchange in memory forever. A zero timeout disables it.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamTimeouts {
+    idle: Option<Duration>,
+    max_duration_deadline: Option<Instant>,
+}
+
+impl StreamTimeouts {
+    pub fn new(config: &EnvConfig, started: Instant) -> Self {
+        let duration = |millis: usize| (millis > 0).then(|| Duration::from_millis(millis as u64));
+        StreamTimeouts {
+            idle: duration(config.stream_idle_timeout),
+            max_duration_deadline: duration(config.stream_max_duration)
+                .map(|max_duration| started + max_duration),
+        }
+    }
+
+    // When the next message must have arrived by, and the reason to give up if it hasn't
+    pub fn next_deadline(&self, now: Instant) -> Option<(Instant, TerminationReason)> {
+        let idle_deadline = self
+            .idle
+            .map(|idle| (now + idle, TerminationReason::IdleTimeout));
+        let max_duration_deadline = self
+            .max_duration_deadline
+            .map(|deadline| (deadline, TerminationReason::MaxDuration));
+
+        match (idle_deadline, max_duration_deadline) {
+            (Some(idle), Some(max_duration)) => Some(if max_duration.0 <= idle.0 {
+                max_duration
+            } else {
+                idle
+            }),
+            (deadline, None) | (None, deadline) => deadline,
+        }
+    }
+}
+
+// Follows the phases of an exchange to emit its event exactly once, when the response is
+// complete. Phases Envoy isn't configured to send are simply never seen: the request headers
+// can be missing (SKIP mode), and without response body or trailers the end of the stream is
+// the end of the response.
+#[derive(Debug, Default)]
+pub struct ExchangeLifecycle {
+    state: ExchangeState,
+    request_seen: bool,
+    termination_reason: Option<TerminationReason>,
+}
+
+impl ExchangeLifecycle {
+    pub fn request_seen(&self) -> bool {
+        self.request_seen
+    }
+
+    pub fn termination_reason(&self) -> Option<TerminationReason> {
+        self.termination_reason
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, ExchangeState::Complete | ExchangeState::Aborted)
+    }
+
+    // Returns true when this phase completes the exchange and its event should be emitted
+    pub fn on_phase(&mut self, phase: Phase) -> bool {
+        if self.is_finished() {
+            log::debug!(
+                "Received {:?} after the exchange finished ({:?}), ignoring it",
+                phase,
+                self.state
+            );
+            return false;
+        }
+
+        let next = match phase {
+            Phase::RequestHeaders { end_of_stream } => {
+                self.request_seen = true;
+                if end_of_stream {
+                    ExchangeState::RequestComplete
+                } else {
+                    ExchangeState::RequestHeaders
+                }
+            }
+            // The upstream can answer before the request is fully sent, later request phases
+            // don't move the exchange back
+            Phase::RequestBody { .. } | Phase::RequestTrailers if self.response_started() => {
+                self.state
+            }
+            Phase::RequestBody {
+                end_of_stream: false,
+            } => ExchangeState::RequestBody,
+            Phase::RequestBody {
+                end_of_stream: true,
+            }
+            | Phase::RequestTrailers => ExchangeState::RequestComplete,
+            Phase::ResponseHeaders {
+                end_of_stream: false,
+            } => ExchangeState::ResponseHeaders,
+            Phase::ResponseBody {
+                end_of_stream: false,
+            } => ExchangeState::ResponseBody,
+            Phase::ResponseHeaders {
+                end_of_stream: true,
+            }
+            | Phase::ResponseBody {
+                end_of_stream: true,
+            }
+            | Phase::ResponseTrailers => ExchangeState::Complete,
+        };
+
+        log::trace!("Exchange state {:?} -> {:?}", self.state, next);
+        self.state = next;
+        self.state == ExchangeState::Complete
+    }
+
+    // The ext_proc stream closed. Returns the final state when the exchange still has to be
+    // emitted, None when it already was or nothing was received. Envoy closes the stream
+    // without a response when the downstream resets, as local replies go through the
+    // response phases like any other response. Once the response started, a graceful close
+    // ends it: a gRPC response closes after its last body chunk when trailers are skipped, and
+    // resets arrive as stream errors instead.
+    pub fn on_close(&mut self) -> Option<ExchangeState> {
+        let next = match self.state {
+            ExchangeState::Start | ExchangeState::Complete | ExchangeState::Aborted => return None,
+            ExchangeState::ResponseHeaders | ExchangeState::ResponseBody => ExchangeState::Complete,
+            ExchangeState::RequestHeaders
+            | ExchangeState::RequestBody
+            | ExchangeState::RequestComplete => {
+                self.termination_reason = Some(TerminationReason::ClientDisconnect);
+                ExchangeState::Aborted
+            }
+        };
+
+        log::trace!(
+            "Stream closed, exchange state {:?} -> {:?}",
+            self.state,
+            next
+        );
+        self.state = next;
+        Some(next)
+    }
+
+    // The ext_proc stream failed or timed out, whatever was received of the exchange is
+    // incomplete
+    pub fn on_termination(&mut self, reason: TerminationReason) -> Option<ExchangeState> {
+        if self.is_finished() || self.state == ExchangeState::Start {
+            return None;
+        }
+
+        log::trace!(
+            "Stream terminated ({}), exchange state {:?} -> {:?}",
+            reason.as_str(),
+            self.state,
+            ExchangeState::Aborted
+        );
+        self.state = ExchangeState::Aborted;
+        self.termination_reason = Some(reason);
+        Some(ExchangeState::Aborted)
+    }
+
+    fn response_started(&self) -> bool {
+        matches!(
+            self.state,
+            ExchangeState::ResponseHeaders | ExchangeState::ResponseBody
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Phase::*;
+
+    // Feed the phases and return the index of each phase that completed the exchange,
+    // followed by the result of closing the stream
+    fn run(phases: &[Phase]) -> (Vec<usize>, Option<ExchangeState>) {
+        let mut lifecycle = ExchangeLifecycle::default();
+        let emitted = phases
+            .iter()
+            .enumerate()
+            .filter(|(_, phase)| lifecycle.on_phase(**phase))
+            .map(|(i, _)| i)
+            .collect();
+        (emitted, lifecycle.on_close())
+    }
+
+    // A GET answered with 204, headers only
+    #[test]
+    fn test_exchange_headers_only() {
+        // arrange
+        let phases = [
+            RequestHeaders {
+                end_of_stream: true,
+            },
+            ResponseHeaders {
+                end_of_stream: true,
+            },
+        ];
+
+        // act
+        let (emitted, closed) = run(&phases);
+
+        // assert
+        assert_eq!(emitted, vec![1]);
+        assert_eq!(closed, None);
+    }
+
+    // Bodies and trailers streamed both ways
+    #[test]
+    fn test_exchange_response_trailers_end_response() {
+        // arrange
+        let phases = [
+            RequestHeaders {
+                end_of_stream: false,
+            },
+            RequestBody {
+                end_of_stream: false,
+            },
+            RequestTrailers,
+            ResponseHeaders {
+                end_of_stream: false,
+            },
+            ResponseBody {
+                end_of_stream: false,
+            },
+            ResponseBody {
+                end_of_stream: false,
+            },
+            ResponseTrailers,
+        ];
+
+        // act
+        let (emitted, closed) = run(&phases);
+
+        // assert
+        assert_eq!(emitted, vec![6]);
+        assert_eq!(closed, None);
+    }
+
+    #[test]
+    fn test_exchange_last_response_body_chunk_ends_response() {
+        // arrange
+        let phases = [
+            RequestHeaders {
+                end_of_stream: false,
+            },
+            RequestBody {
+                end_of_stream: true,
+            },
+            ResponseHeaders {
+                end_of_stream: false,
+            },
+            ResponseBody {
+                end_of_stream: true,
+            },
+        ];
+
+        // act
+        let (emitted, closed) = run(&phases);
+
+        // assert
+        assert_eq!(emitted, vec![3]);
+        assert_eq!(closed, None);
+    }
+
+    // The response body isn't sent to the processor
+    #[test]
+    fn test_exchange_without_response_body_ends_on_close() {
+        // arrange
+        let phases = [
+            RequestHeaders {
+                end_of_stream: true,
+            },
+            ResponseHeaders {
+                end_of_stream: false,
+            },
+        ];
+
+        // act
+        let (emitted, closed) = run(&phases);
+
+        // assert
+        assert!(emitted.is_empty());
+        assert_eq!(closed, Some(ExchangeState::Complete));
+    }
+
+    // The upstream answers while the request body is still streaming
+    #[test]
+    fn test_exchange_response_before_request_body_ends() {
+        // arrange
+        let phases = [
+            RequestHeaders {
+                end_of_stream: false,
+            },
+            ResponseHeaders {
+                end_of_stream: false,
+            },
+            RequestBody {
+                end_of_stream: true,
+            },
+            ResponseBody {
+                end_of_stream: true,
+            },
+        ];
+
+        // act
+        let (emitted, closed) = run(&phases);
+
+        // assert
+        assert_eq!(emitted, vec![3]);
+        assert_eq!(closed, None);
+    }
+
+    // Request headers skipped by the processing mode
+    #[test]
+    fn test_exchange_without_request_headers() {
+        // arrange
+        let phases = [
+            ResponseHeaders {
+                end_of_stream: false,
+            },
+            ResponseTrailers,
+        ];
+
+        // act
+        let (emitted, closed) = run(&phases);
+
+        // assert
+        assert_eq!(emitted, vec![1]);
+        assert_eq!(closed, None);
+    }
+
+    #[test]
+    fn test_exchange_closed_before_response() {
+        // arrange
+        let phases = [
+            RequestHeaders {
+                end_of_stream: false,
+            },
+            RequestBody {
+                end_of_stream: false,
+            },
+        ];
+
+        // act
+        let (emitted, closed) = run(&phases);
+
+        // assert
+        assert!(emitted.is_empty());
+        assert_eq!(closed, Some(ExchangeState::Aborted));
+    }
+
+    #[test]
+    fn test_exchange_closed_before_any_phase() {
+        // arrange
+        let phases = [];
+
+        // act
+        let (emitted, closed) = run(&phases);
+
+        // assert
+        assert!(emitted.is_empty());
+        assert_eq!(closed, None);
+    }
+
+    #[test]
+    fn test_exchange_ignores_phases_after_completion() {
+        // arrange
+        let phases = [
+            RequestHeaders {
+                end_of_stream: true,
+            },
+            ResponseHeaders {
+                end_of_stream: true,
+            },
+            ResponseTrailers,
+        ];
+
+        // act
+        let (emitted, closed) = run(&phases);
+
+        // assert
+        assert_eq!(emitted, vec![1]);
+        assert_eq!(closed, None);
+    }
+
+    #[test]
+    fn test_exchange_termination() {
+        // arrange
+        let mut disconnected = ExchangeLifecycle::default();
+        let mut timed_out = ExchangeLifecycle::default();
+        let mut completed = ExchangeLifecycle::default();
+        let mut closed_mid_response = ExchangeLifecycle::default();
+        let mut reset_mid_response = ExchangeLifecycle::default();
+        let mut event = Event::default();
+        let mut reset_event = Event {
+            response: Some(ResponseInfo {
+                status: 200,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // act
+        disconnected.on_phase(Phase::RequestHeaders {
+            end_of_stream: true,
+        });
+        let disconnected_state = disconnected.on_close();
+        timed_out.on_phase(Phase::RequestHeaders {
+            end_of_stream: true,
+        });
+        let timed_out_state = timed_out.on_termination(TerminationReason::for_status(
+            &Status::deadline_exceeded("timeout"),
+        ));
+        completed.on_phase(Phase::ResponseHeaders {
+            end_of_stream: true,
+        });
+        let completed_state = completed.on_termination(TerminationReason::StreamError);
+        TerminationReason::ClientDisconnect.apply_to_event(&mut event);
+        for lifecycle in [&mut closed_mid_response, &mut reset_mid_response] {
+            lifecycle.on_phase(ResponseHeaders {
+                end_of_stream: false,
+            });
+            lifecycle.on_phase(ResponseBody {
+                end_of_stream: false,
+            });
+        }
+        let closed_mid_response_state = closed_mid_response.on_close();
+        let reset_mid_response_state = reset_mid_response
+            .on_termination(TerminationReason::for_status(&Status::cancelled("reset")));
+        if let Some(reason) = reset_mid_response.termination_reason() {
+            reason.apply_to_event(&mut reset_event);
+        }
+
+        // assert
+        assert_eq!(disconnected_state, Some(ExchangeState::Aborted));
+        assert_eq!(
+            disconnected.termination_reason(),
+            Some(TerminationReason::ClientDisconnect)
+        );
+        assert_eq!(timed_out_state, Some(ExchangeState::Aborted));
+        assert_eq!(
+            timed_out.termination_reason(),
+            Some(TerminationReason::GatewayTimeout)
+        );
+        assert_eq!(completed_state, None);
+        assert_eq!(completed.termination_reason(), None);
+        assert_eq!(event.metadata["termination_reason"], "client_disconnect");
+        assert_eq!(event.response.unwrap().status, 499);
+        assert_eq!(closed_mid_response_state, Some(ExchangeState::Complete));
+        assert_eq!(closed_mid_response.termination_reason(), None);
+        assert_eq!(reset_mid_response_state, Some(ExchangeState::Aborted));
+        assert_eq!(
+            reset_event.metadata["termination_reason"],
+            "client_disconnect"
+        );
+        assert_eq!(reset_event.response.unwrap().status, 200);
+    }
+
+    #[test]
+    fn test_stream_timeouts() {
+        // arrange
+        let started = Instant::now();
+        let config = EnvConfig {
+            stream_idle_timeout: 1000,
+            stream_max_duration: 5000,
+            ..Default::default()
+        };
+        let timeouts = StreamTimeouts::new(&config, started);
+        let mut event = Event {
+            response: Some(ResponseInfo {
+                status: 101,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // act
+        let early = timeouts.next_deadline(started);
+        let late = timeouts.next_deadline(started + Duration::from_millis(4500));
+        let disabled = StreamTimeouts::new(&EnvConfig::default(), started).next_deadline(started);
+        TerminationReason::IdleTimeout.apply_to_event(&mut event);
+
+        // assert
+        assert_eq!(
+            early,
+            Some((
+                started + Duration::from_millis(1000),
+                TerminationReason::IdleTimeout
+            ))
+        );
+        assert_eq!(
+            late,
+            Some((
+                started + Duration::from_millis(5000),
+                TerminationReason::MaxDuration
+            ))
+        );
+        assert_eq!(disabled, None);
+        assert_eq!(event.metadata["termination_reason"], "idle_timeout");
+        assert_eq!(event.metadata["incomplete"], true);
+        assert_eq!(event.response.unwrap().status, 101);
+    }
+}
@@ -0,0 +1,422 @@
+use serde_json::{json, Value};
+
+use crate::body::BodyBuffer;
+use crate::compression::decompress;
+use crate::config::Config;
+use crate::event::Event;
+use crate::sse::SseParser;
+
+// Model, token counts and finish reason reported by an LLM provider in its response
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct LlmUsage {
+    // Known from the endpoint, or else from the shape of the response
+    pub provider: Option<&'static str>,
+    pub model: Option<String>,
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+    pub total_tokens: Option<i64>,
+    pub finish_reason: Option<String>,
+}
+
+impl LlmUsage {
+    // Fold a response body, or one chunk of a streamed response, into the usage. Providers
+    // report usage in different places and streams spread it across chunks, so fields found
+    // later replace earlier ones and fields not found are left alone.
+    pub fn observe(&mut self, value: &Value) {
+        if let Some(items) = value.as_array() {
+            // Gemini streams a JSON array of responses without alt=sse
+            items.iter().for_each(|item| self.observe(item));
+            return;
+        }
+
+        // OpenAI Responses API stream events and Anthropic message_start wrap the response
+        for wrapper in ["response", "message"] {
+            if let Some(inner) = value.get(wrapper).filter(|inner| inner.is_object()) {
+                self.observe(inner);
+            }
+        }
+
+        if let Some(model) = string_field(value, &["model", "modelVersion"]) {
+            self.model = Some(model);
+        }
+
+        // OpenAI and Anthropic
+        if let Some(usage) = value.get("usage").filter(|usage| usage.is_object()) {
+            // Anthropic counts input and output tokens without a total, OpenAI's Responses API
+            // has a total
+            let anthropic =
+                usage.get("input_tokens").is_some() && usage.get("total_tokens").is_none();
+            self.provider
+                .get_or_insert(if anthropic { "anthropic" } else { "openai" });
+            self.set_counts(
+                int_field(usage, &["prompt_tokens", "input_tokens"]),
+                int_field(usage, &["completion_tokens", "output_tokens"]),
+                int_field(usage, &["total_tokens"]),
+            );
+        }
+        // Gemini
+        if let Some(usage) = value.get("usageMetadata") {
+            self.provider.get_or_insert("gemini");
+            self.set_counts(
+                int_field(usage, &["promptTokenCount"]),
+                int_field(usage, &["candidatesTokenCount"]),
+                int_field(usage, &["totalTokenCount"]),
+            );
+        }
+
+        let finish_reason = value
+            .get("choices")
+            .or_else(|| value.get("candidates"))
+            .and_then(Value::as_array)
+            .and_then(|choices| {
+                choices
+                    .iter()
+                    .find_map(|choice| string_field(choice, &["finish_reason", "finishReason"]))
+            })
+            .or_else(|| string_field(value, &["stop_reason"]))
+            // Anthropic message_delta
+            .or_else(|| {
+                value
+                    .get("delta")
+                    .and_then(|d| string_field(d, &["stop_reason"]))
+            })
+            .or_else(|| {
+                // OpenAI Responses API
+                (value.get("object").and_then(Value::as_str) == Some("response"))
+                    .then(|| string_field(value, &["status"]))
+                    .flatten()
+            });
+        if finish_reason.is_some() {
+            self.finish_reason = finish_reason;
+        }
+    }
+
+    fn set_counts(&mut self, prompt: Option<i64>, completion: Option<i64>, total: Option<i64>) {
+        self.prompt_tokens = prompt.or(self.prompt_tokens);
+        self.completion_tokens = completion.or(self.completion_tokens);
+        self.total_tokens = total.or(self.total_tokens);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.model.is_none()
+            && self.prompt_tokens.is_none()
+            && self.completion_tokens.is_none()
+            && self.finish_reason.is_none()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let total_tokens = self.total_tokens.or_else(|| {
+            // Anthropic doesn't report a total
+            Some(self.prompt_tokens? + self.completion_tokens?)
+        });
+
+        json!({
+            "provider": self.provider,
+            "model": self.model,
+            "prompt_tokens": self.prompt_tokens,
+            "completion_tokens": self.completion_tokens,
+            "total_tokens": total_tokens,
+            "finish_reason": self.finish_reason,
+        })
+    }
+}
+
+// Reads the token usage out of the response body of a known LLM API, streamed or not
+#[derive(Debug)]
+pub struct LlmUsageCapture {
+    usage: LlmUsage,
+    // None until the response headers tell whether the body is an event stream
+    sse: Option<SseParser>,
+    buffer: BodyBuffer,
+    enabled: bool,
+}
+
+impl LlmUsageCapture {
+    // LLM APIs are called with a JSON POST request, to one of the standard endpoints or to a
+    // path listed in llm_paths
+    pub fn for_request(config: &Config, event: &Event) -> Option<Self> {
+        if !config.env.llm_usage || !event.request.verb.eq_ignore_ascii_case("POST") {
+            return None;
+        }
+        let json_request = event
+            .request
+            .headers
+            .get("content-type")
+            .is_some_and(|ct| ct.to_ascii_lowercase().contains("json"));
+        if !json_request {
+            return None;
+        }
+
+        let uri = &event.request.uri;
+        let provider = provider_for_path(uri);
+        if provider.is_none() && !config.llm_paths.iter().any(|regex| regex.is_match(uri)) {
+            return None;
+        }
+        log::trace!("Capturing {:?} token usage for {}", provider, uri);
+        Some(LlmUsageCapture {
+            usage: LlmUsage {
+                provider,
+                ..Default::default()
+            },
+            sse: None,
+            buffer: BodyBuffer::default(),
+            enabled: true,
+        })
+    }
+
+    pub fn on_response_headers(&mut self, event: &Event) {
+        let content_type = event
+            .response
+            .as_ref()
+            .and_then(|response| response.headers.get("content-type"))
+            .map(|ct| ct.to_ascii_lowercase())
+            .unwrap_or_default();
+
+        if content_type.starts_with("text/event-stream") {
+            let content_encoding = event
+                .response
+                .as_ref()
+                .and_then(|response| response.headers.get("content-encoding"));
+            self.sse = SseParser::for_content_encoding(content_encoding.map(String::as_str));
+            self.enabled = self.sse.is_some();
+        } else if !content_type.contains("json") {
+            self.enabled = false;
+        }
+    }
+
+    pub fn append_chunk(&mut self, config: &Config, chunk: &[u8]) {
+        if !self.enabled {
+            return;
+        }
+
+        match self.sse.as_mut() {
+            // Stream events are parsed as they come in, the stream itself isn't kept
+            Some(parser) => {
+                for event in parser.push(chunk, config.env.max_body_size) {
+                    observe_sse_data(&mut self.usage, &event.data);
+                }
+            }
+            None => self.buffer.append(chunk, config.env.max_body_size),
+        }
+    }
+
+    pub fn apply_to_event(&mut self, config: &Config, event: &mut Event) {
+        if !self.enabled {
+            return;
+        }
+
+        match self.sse.as_mut() {
+            Some(parser) => {
+                for event in parser.finish(config.env.max_body_size) {
+                    observe_sse_data(&mut self.usage, &event.data);
+                }
+            }
+            None => {
+                let content_encoding = event
+                    .response
+                    .as_ref()
+                    .and_then(|response| response.headers.get("content-encoding"));
+                let data = match content_encoding {
+                    Some(encoding) => {
+                        decompress(encoding, &self.buffer.data, config.env.max_body_size).ok()
+                    }
+                    None => Some(self.buffer.data.clone()),
+                };
+                if let Some(value) =
+                    data.and_then(|data| serde_json::from_slice::<Value>(&data).ok())
+                {
+                    self.usage.observe(&value);
+                }
+            }
+        }
+
+        if !self.usage.is_empty() {
+            event.set_metadata("llm", self.usage.to_json());
+        }
+    }
+}
+
+fn observe_sse_data(usage: &mut LlmUsage, data: &str) {
+    // OpenAI ends its streams with a literal "[DONE]"
+    if let Ok(value) = serde_json::from_str::<Value>(data) {
+        usage.observe(&value);
+    }
+}
+
+// The provider of the API, from the path of the standard endpoints, including Azure OpenAI
+// deployments
+pub fn provider_for_path(uri: &str) -> Option<&'static str> {
+    let path = uri.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').collect();
+    // "v1", "v1beta", "v2alpha"
+    let is_version = |segment: &str| {
+        segment.strip_prefix('v').is_some_and(|rest| {
+            rest.starts_with(|c: char| c.is_ascii_digit())
+                && rest.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+    };
+
+    match segments.as_slice() {
+        [.., version, "models", method]
+            if is_version(version)
+                && (method.ends_with(":generateContent")
+                    || method.ends_with(":streamGenerateContent")) =>
+        {
+            Some("gemini")
+        }
+        [.., version, "messages"] if is_version(version) => Some("anthropic"),
+        [.., version, "chat", "completions"] if is_version(version) => Some("openai"),
+        [.., version, "completions" | "responses" | "embeddings"] if is_version(version) => {
+            Some("openai")
+        }
+        [.., "openai", "deployments", _, "chat", "completions"]
+        | [.., "openai", "deployments", _, "completions" | "embeddings"] => Some("openai"),
+        _ => None,
+    }
+}
+
+fn string_field(value: &Value, names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| value.get(name)?.as_str())
+        .map(str::to_string)
+}
+
+fn int_field(value: &Value, names: &[&str]) -> Option<i64> {
+    names.iter().find_map(|name| value.get(name)?.as_i64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EnvConfig;
+    use crate::event::ResponseInfo;
+
+    fn llm_request(uri: &str) -> Event {
+        let mut event = Event::default();
+        event.request.verb = "POST".to_string();
+        event.request.uri = uri.to_string();
+        event
+            .request
+            .headers
+            .insert("content-type".to_string(), "application/json".to_string());
+        event
+    }
+
+    fn capture_response(uri: &str, content_type: &str, chunks: &[&str]) -> Value {
+        let cfg = Config::new(EnvConfig {
+            llm_usage: true,
+            llm_paths: Some("^/anthropic$".to_string()),
+            max_body_size: 100_000,
+            ..Default::default()
+        });
+        let mut event = llm_request(uri);
+        let mut response = ResponseInfo::default();
+        response
+            .headers
+            .insert("content-type".to_string(), content_type.to_string());
+        event.response = Some(response);
+
+        let mut capture = LlmUsageCapture::for_request(&cfg, &event).unwrap();
+        capture.on_response_headers(&event);
+        for chunk in chunks {
+            capture.append_chunk(&cfg, chunk.as_bytes());
+        }
+        capture.apply_to_event(&cfg, &mut event);
+        event.metadata["llm"].clone()
+    }
+
+    #[test]
+    fn test_llm_usage_from_buffered_and_streamed_responses() {
+        // arrange
+        let openai = r#"{"model":"gpt-4o","choices":[{"finish_reason":"stop"}],
+            "usage":{"prompt_tokens":12,"completion_tokens":30,"total_tokens":42}}"#;
+        let anthropic = [
+            "event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"model\":\"claude-sonnet-4\",",
+            "\"usage\":{\"input_tokens\":25,\"output_tokens\":1}}}\n\n",
+            "event: message_delta\ndata: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"},",
+            "\"usage\":{\"output_tokens\":15}}\n\n",
+        ];
+        let gemini = "data: {\"candidates\":[{\"finishReason\":\"STOP\"}],\"modelVersion\":\"gemini-2.0-flash\",\
+            \"usageMetadata\":{\"promptTokenCount\":4,\"candidatesTokenCount\":6,\"totalTokenCount\":10}}\r\n\r\n";
+
+        // act
+        let openai_usage = capture_response("/v1/chat/completions", "application/json", &[openai]);
+        let anthropic_usage = capture_response("/v1/messages", "text/event-stream", &anthropic);
+        let gemini_usage = capture_response(
+            "/v1beta/models/gemini-2.0-flash:streamGenerateContent?alt=sse",
+            "text/event-stream",
+            &[gemini],
+        );
+
+        // assert
+        assert_eq!(openai_usage["provider"], "openai");
+        assert_eq!(openai_usage["total_tokens"], 42);
+        assert_eq!(openai_usage["finish_reason"], "stop");
+        assert_eq!(
+            anthropic_usage,
+            json!({
+                "provider": "anthropic",
+                "model": "claude-sonnet-4",
+                "prompt_tokens": 25,
+                "completion_tokens": 15,
+                "total_tokens": 40,
+                "finish_reason": "end_turn",
+            })
+        );
+        assert_eq!(gemini_usage["model"], "gemini-2.0-flash");
+        assert_eq!(gemini_usage["completion_tokens"], 6);
+        assert_eq!(gemini_usage["finish_reason"], "STOP");
+    }
+
+    #[test]
+    fn test_llm_capture_only_for_provider_endpoints() {
+        // arrange
+        let cfg = Config::new(EnvConfig {
+            llm_usage: true,
+            ..Default::default()
+        });
+        let mut get_request = llm_request("/v1/chat/completions");
+        get_request.request.verb = "GET".to_string();
+
+        // act
+        let conversation =
+            LlmUsageCapture::for_request(&cfg, &llm_request("/conversations/42/messages"));
+        let unversioned = LlmUsageCapture::for_request(&cfg, &llm_request("/api/responses"));
+        let gateway_prefixed =
+            LlmUsageCapture::for_request(&cfg, &llm_request("/openai/v1/responses"));
+        let azure = LlmUsageCapture::for_request(
+            &cfg,
+            &llm_request("/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21"),
+        );
+        let not_post = LlmUsageCapture::for_request(&cfg, &get_request);
+
+        // assert
+        assert!(conversation.is_none());
+        assert!(unversioned.is_none());
+        assert!(gateway_prefixed.is_some());
+        assert_eq!(azure.unwrap().usage.provider, Some("openai"));
+        assert!(not_post.is_none());
+    }
+
+    #[test]
+    fn test_llm_provider_from_response_on_configured_paths() {
+        // arrange
+        let anthropic = r#"{"model":"claude-sonnet-4","stop_reason":"end_turn",
+            "usage":{"input_tokens":10,"output_tokens":5}}"#;
+        let cfg = Config::new(EnvConfig {
+            llm_usage: true,
+            llm_paths: Some("^/anthropic$".to_string()),
+            ..Default::default()
+        });
+
+        // act
+        let rewritten = capture_response("/anthropic", "application/json", &[anthropic]);
+        let unlisted = LlmUsageCapture::for_request(&cfg, &llm_request("/anthropic/admin"));
+
+        // assert
+        assert_eq!(rewritten["provider"], "anthropic");
+        assert_eq!(rewritten["total_tokens"], 15);
+        assert!(unlisted.is_none());
+    }
+}
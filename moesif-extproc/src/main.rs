@@ -1,7 +1,22 @@
+mod application_id;
+mod body;
+mod body_format;
+mod compression;
 mod config;
 mod event;
+mod graphql;
+mod grpc_payload;
 mod grpc_service;
+mod id_source;
+mod jwt;
+mod lifecycle;
+mod llm;
+mod metadata;
 mod root_context;
+mod route_policy;
+mod sizes;
+mod sse;
+mod timing;
 mod utils;
 
 use crate::config::{Config, EnvConfig};
@@ -36,9 +51,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Initialize configuration
     let env_config = EnvConfig::new();
-    let config = Config {
-        env: env_config,
-    };
+    let config = Config::new(env_config);
 
     let runtime = tokio::runtime::Runtime::new()?;
     runtime.block_on(async_main(config))
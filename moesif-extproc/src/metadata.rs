@@ -0,0 +1,226 @@
+use envoy_ext_proc_proto::envoy::config::core::v3::Metadata;
+use prost_types::value::Kind;
+use prost_types::{Struct, Value};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::Config;
+use crate::event::Event;
+use crate::utils::session_token_value;
+
+// Namespace of the dynamic metadata returned to Envoy
+pub const DYNAMIC_METADATA_NAMESPACE: &str = "moesif";
+
+// Resolve a "<namespace>:<dot.separated.path>" reference (e.g.
+// "envoy.filters.http.ext_authz:principal.user_id") against the filter metadata
+pub fn filter_metadata_string(metadata: &Metadata, reference: &str) -> Option<String> {
+    let (namespace, path) = match reference.split_once(':') {
+        Some(parts) => parts,
+        None => {
+            log::warn!(
+                "Invalid metadata reference {:?}, expected <namespace>:<path>",
+                reference
+            );
+            return None;
+        }
+    };
+
+    let fields = metadata.filter_metadata.get(namespace)?;
+    lookup_path(fields, path).and_then(value_as_string)
+}
+
+pub fn lookup_path<'a>(fields: &'a Struct, path: &str) -> Option<&'a Value> {
+    let mut segments = path.split('.');
+    let mut current = fields.fields.get(segments.next()?)?;
+
+    for segment in segments {
+        current = match &current.kind {
+            Some(Kind::StructValue(nested)) => nested.fields.get(segment)?,
+            _ => return None,
+        };
+    }
+
+    Some(current)
+}
+
+pub fn value_as_string(value: &Value) -> Option<String> {
+    match &value.kind {
+        Some(Kind::StringValue(s)) if !s.is_empty() => Some(s.clone()),
+        // Struct numbers are doubles, keep integral ids free of a trailing ".0"
+        Some(Kind::NumberValue(n)) if n.fract() == 0.0 && n.abs() < 1e15 => {
+            Some((*n as i64).to_string())
+        }
+        Some(Kind::NumberValue(n)) => Some(n.to_string()),
+        Some(Kind::BoolValue(b)) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+pub fn value_to_json(value: &Value) -> serde_json::Value {
+    match &value.kind {
+        Some(Kind::StringValue(s)) => serde_json::Value::String(s.clone()),
+        // Struct numbers are doubles, keep integral values integers
+        Some(Kind::NumberValue(n)) if n.fract() == 0.0 && n.abs() < 1e15 => {
+            serde_json::Value::from(*n as i64)
+        }
+        Some(Kind::NumberValue(n)) => serde_json::Number::from_f64(*n)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        Some(Kind::BoolValue(b)) => serde_json::Value::Bool(*b),
+        Some(Kind::StructValue(s)) => struct_to_json(s),
+        Some(Kind::ListValue(l)) => {
+            serde_json::Value::Array(l.values.iter().map(value_to_json).collect())
+        }
+        Some(Kind::NullValue(_)) | None => serde_json::Value::Null,
+    }
+}
+
+pub fn struct_to_json(fields: &Struct) -> serde_json::Value {
+    serde_json::Value::Object(
+        fields
+            .fields
+            .iter()
+            .map(|(k, v)| (k.clone(), value_to_json(v)))
+            .collect(),
+    )
+}
+
+// Attributes are grouped by the namespace of the filter that produced them,
+// look the attribute up regardless of namespace
+pub fn find_attribute<'a>(
+    attributes: &'a HashMap<String, Struct>,
+    name: &str,
+) -> Option<&'a Value> {
+    attributes
+        .values()
+        .find_map(|attributes| attributes.fields.get(name))
+}
+
+// Identify the event from dynamic metadata written by earlier filters such as ext-auth.
+// Values found here take precedence over the ones read from the request itself.
+pub fn add_metadata_identity_to_event(
+    config: &Config,
+    event: &mut Event,
+    metadata: Option<&Metadata>,
+) {
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => return,
+    };
+    log::trace!("Metadata context: {:?}", metadata);
+
+    if let Some(reference) = &config.env.user_id_metadata {
+        if let Some(user_id) = filter_metadata_string(metadata, reference) {
+            event.user_id = Some(user_id);
+        }
+    }
+
+    if let Some(reference) = &config.env.company_id_metadata {
+        if let Some(company_id) = filter_metadata_string(metadata, reference) {
+            event.company_id = Some(company_id);
+        }
+    }
+
+    if let Some(reference) = &config.env.session_token_metadata {
+        if let Some(session_token) = filter_metadata_string(metadata, reference) {
+            event.session_token = Some(session_token_value(config, &session_token));
+        }
+    }
+
+    log::trace!(
+        "Identity after metadata lookup: user_id={:?} company_id={:?}",
+        event.user_id,
+        event.company_id
+    );
+}
+
+// The identity resolved for the exchange and whether it is logged, returned to Envoy as
+// dynamic metadata for access logs, rate limiting and the filters after this one
+pub fn dynamic_metadata_for(event: &Event, logged: bool) -> Struct {
+    let string_value = |s: &str| Value {
+        kind: Some(Kind::StringValue(s.to_string())),
+    };
+
+    let mut fields = BTreeMap::new();
+    if let Some(user_id) = &event.user_id {
+        fields.insert("user_id".to_string(), string_value(user_id));
+    }
+    if let Some(company_id) = &event.company_id {
+        fields.insert("company_id".to_string(), string_value(company_id));
+    }
+    fields.insert(
+        "logged".to_string(),
+        Value {
+            kind: Some(Kind::BoolValue(logged)),
+        },
+    );
+
+    Struct {
+        fields: BTreeMap::from([(
+            DYNAMIC_METADATA_NAMESPACE.to_string(),
+            Value {
+                kind: Some(Kind::StructValue(Struct { fields })),
+            },
+        )]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EnvConfig;
+
+    fn string_value(s: &str) -> Value {
+        Value {
+            kind: Some(Kind::StringValue(s.to_string())),
+        }
+    }
+
+    #[test]
+    fn test_add_metadata_identity_to_event() {
+        // arrange
+        let principal = Struct {
+            fields: BTreeMap::from([
+                ("user_id".to_string(), string_value("user-1")),
+                (
+                    "org".to_string(),
+                    Value {
+                        kind: Some(Kind::NumberValue(42.0)),
+                    },
+                ),
+            ]),
+        };
+        let ext_authz = Struct {
+            fields: BTreeMap::from([(
+                "principal".to_string(),
+                Value {
+                    kind: Some(Kind::StructValue(principal)),
+                },
+            )]),
+        };
+        let metadata = Metadata {
+            filter_metadata: HashMap::from([(
+                "envoy.filters.http.ext_authz".to_string(),
+                ext_authz,
+            )]),
+            ..Default::default()
+        };
+        let cfg = Config::new(EnvConfig {
+            user_id_metadata: Some("envoy.filters.http.ext_authz:principal.user_id".to_string()),
+            company_id_metadata: Some("envoy.filters.http.ext_authz:principal.org".to_string()),
+            session_token_metadata: Some("envoy.filters.http.ext_authz:session".to_string()),
+            ..Default::default()
+        });
+        let mut event = Event {
+            user_id: Some("from-header".to_string()),
+            ..Default::default()
+        };
+
+        // act
+        add_metadata_identity_to_event(&cfg, &mut event, Some(&metadata));
+
+        // assert
+        assert_eq!(event.user_id, Some("user-1".to_string()));
+        assert_eq!(event.company_id, Some("42".to_string()));
+        assert_eq!(event.session_token, None);
+    }
+}
@@ -5,6 +5,7 @@ use reqwest::{Client, Method};
 
 use crate::event::Event;
 use bytes::Bytes;
+use std::collections::HashMap;
 use std::time::Duration;
 use tokio::sync::Mutex;
 
@@ -13,7 +14,9 @@ type CallbackType = Box<dyn Fn(Vec<(String, String)>, Option<Vec<u8>>) + Send>;
 #[derive(Default)]
 pub struct EventRootContext {
     pub config: Config,
-    pub event_byte_buffer: Mutex<Vec<Bytes>>, // Holds serialized, complete events
+    // Holds serialized, complete events, queued per Moesif application id so a batch
+    // never mixes applications
+    pub event_byte_buffer: Mutex<HashMap<String, Vec<Bytes>>>,
     // context_id: String,
     is_start: bool,
 }
@@ -22,7 +25,7 @@ impl EventRootContext {
     pub fn new(config: Config) -> Self {
         EventRootContext {
             config,
-            event_byte_buffer: Mutex::new(Vec::new()),
+            event_byte_buffer: Mutex::new(HashMap::new()),
             // context_id: String::new(),
             is_start: true,
         }
@@ -70,9 +73,10 @@ impl EventRootContext {
 
         {
             let buffer = self.event_byte_buffer.lock().await;
+            let largest_queue = buffer.values().map(Vec::len).max().unwrap_or(0);
             log::trace!(
-                "Acquired lock on event_byte_buffer. Current buffer size: {}",
-                buffer.len()
+                "Acquired lock on event_byte_buffer. Largest queue size: {}",
+                largest_queue
             );
 
             if self.is_start {
@@ -80,7 +84,7 @@ impl EventRootContext {
                 immediate_send = true;
                 self.is_start = false; // Ensure this block only runs once
                 log::trace!("First event processed, setting is_start to false.");
-            } else if buffer.len() >= self.config.env.batch_max_size {
+            } else if largest_queue >= self.config.env.batch_max_size {
                 // Buffer full, send immediately
                 immediate_send = true;
                 log::trace!("Buffer size has reached maximum capacity, triggering flush.");
@@ -103,62 +107,70 @@ impl EventRootContext {
             match self.event_byte_buffer.try_lock() {
                 Ok(mut buffer) => {
                     log::trace!(
-                        "Acquired lock on event_byte_buffer for draining after {} attempts. Current buffer size: {}",
+                        "Acquired lock on event_byte_buffer for draining after {} attempts. Queues: {}",
                         attempts, buffer.len()
                     );
 
-                    while buffer.len() >= drain_at_least {
-                        log::trace!(
-                            "Buffer size {} >= {}. Draining and sending events.",
-                            buffer.len(),
-                            drain_at_least
-                        );
-
-                        log::trace!("Config batch_max_size: {}", self.config.env.batch_max_size);
-                        let end = std::cmp::min(buffer.len(), self.config.env.batch_max_size);
-                        log::trace!("Calculated end for draining: {}", end);
-
-                        let events_to_send: Vec<Bytes> = buffer.drain(..end).collect();
-                        log::trace!(
-                            "Drained {} events from buffer for sending.",
-                            events_to_send.len()
-                        );
-                        log::trace!("Buffer size after draining: {}", buffer.len());
-
-                        let body = self.write_events_json(events_to_send).await;
-
-                        log::info!("Dispatching HTTP request with {} events.", end);
-
-                        if let Err(e) = self
-                            .dispatch_http_request(
-                                "POST",
-                                "/v1/events/batch",
-                                body,
-                                Box::new(|headers, _| {
-                                    let config_etag = get_header(&headers, "X-Moesif-Config-Etag");
-                                    let rules_etag = get_header(&headers, "X-Moesif-Rules-Etag");
-                                    log::info!(
-                                        "Event Response eTags: config={:?} rules={:?}",
-                                        config_etag,
-                                        rules_etag
-                                    );
-                                }),
-                            )
-                            .await
-                        {
-                            log::error!("Failed to dispatch HTTP request: {:?}", e);
+                    for (application_id, queue) in buffer.iter_mut() {
+                        while queue.len() >= drain_at_least {
+                            log::trace!(
+                                "Queue size {} >= {}. Draining and sending events.",
+                                queue.len(),
+                                drain_at_least
+                            );
+
+                            log::trace!(
+                                "Config batch_max_size: {}",
+                                self.config.env.batch_max_size
+                            );
+                            let end = std::cmp::min(queue.len(), self.config.env.batch_max_size);
+                            log::trace!("Calculated end for draining: {}", end);
+
+                            let events_to_send: Vec<Bytes> = queue.drain(..end).collect();
+                            log::trace!(
+                                "Drained {} events from queue for sending.",
+                                events_to_send.len()
+                            );
+                            log::trace!("Queue size after draining: {}", queue.len());
+
+                            let body = self.write_events_json(events_to_send).await;
+
+                            log::info!("Dispatching HTTP request with {} events.", end);
+
+                            if let Err(e) = self
+                                .dispatch_http_request(
+                                    "POST",
+                                    "/v1/events/batch",
+                                    application_id,
+                                    body,
+                                    Box::new(|headers, _| {
+                                        let config_etag =
+                                            get_header(&headers, "X-Moesif-Config-Etag");
+                                        let rules_etag =
+                                            get_header(&headers, "X-Moesif-Rules-Etag");
+                                        log::info!(
+                                            "Event Response eTags: config={:?} rules={:?}",
+                                            config_etag,
+                                            rules_etag
+                                        );
+                                    }),
+                                )
+                                .await
+                            {
+                                log::error!("Failed to dispatch HTTP request: {:?}", e);
+                            }
+
+                            log::trace!(
+                                "Events drained and sent. Current queue size: {}",
+                                queue.len()
+                            );
                         }
-
-                        log::trace!(
-                            "Events drained and sent. Current buffer size: {}",
-                            buffer.len()
-                        );
                     }
 
-                    log::trace!(
-                        "Exiting drain_and_send. Current buffer size: {}",
-                        buffer.len()
-                    );
+                    // Applications that had no traffic since the last flush
+                    buffer.retain(|_, queue| !queue.is_empty());
+
+                    log::trace!("Exiting drain_and_send. Remaining queues: {}", buffer.len());
                     break;
                 }
                 Err(_) => {
@@ -170,9 +182,12 @@ impl EventRootContext {
         }
     }
 
-    pub async fn push_event(&mut self, event: &Event) {
+    pub async fn push_event(&mut self, application_id: &str, event: &Event) {
         let mut buffer = self.event_byte_buffer.lock().await;
-        buffer.push(serialize_event_to_bytes(event));
+        buffer
+            .entry(application_id.to_string())
+            .or_default()
+            .push(serialize_event_to_bytes(event));
         log::trace!("Event pushed to event_byte_buffer.");
     }
 
@@ -180,6 +195,7 @@ impl EventRootContext {
         &self,
         method: &str,
         path: &str,
+        application_id: &str,
         body: Bytes,
         callback: CallbackType,
     ) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
@@ -198,7 +214,7 @@ impl EventRootContext {
         );
         headers.insert(
             HeaderName::from_static("x-moesif-application-id"),
-            HeaderValue::from_str(&self.config.env.moesif_application_id)?,
+            HeaderValue::from_str(application_id)?,
         );
 
         let curl_cmd = generate_curl_command(method.as_str(), &url, &headers, Some(&body));
@@ -238,3 +254,42 @@ impl EventRootContext {
         Ok(12345) // Replace with actual token or ID logic if needed
     }
 }
+
+#[cfg(test)]
+impl EventRootContext {
+    // Keep the first event queued instead of sending it right away, so tests can read it back
+    pub fn skip_initial_flush(&mut self) {
+        self.is_start = false;
+    }
+
+    pub async fn queued_events(&self) -> Vec<serde_json::Value> {
+        let buffer = self.event_byte_buffer.lock().await;
+        buffer
+            .values()
+            .flatten()
+            .map(|event| serde_json::from_slice(event).unwrap())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_push_event_queues_per_application_id() {
+        // arrange
+        let mut root_context = EventRootContext::new(Config::default());
+
+        // act
+        root_context.push_event("app-a", &Event::default()).await;
+        root_context.push_event("app-b", &Event::default()).await;
+        root_context.push_event("app-a", &Event::default()).await;
+
+        // assert
+        let buffer = root_context.event_byte_buffer.lock().await;
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer["app-a"].len(), 2);
+        assert_eq!(buffer["app-b"].len(), 1);
+    }
+}
@@ -0,0 +1,238 @@
+use envoy_ext_proc_proto::envoy::service::ext_proc::v3::ProcessingRequest;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+use crate::metadata::{find_attribute, struct_to_json, value_to_json};
+
+// Per-route overrides of the global configuration, set by app teams as filter metadata on
+// their routes (e.g. Gloo RouteOptions) under the `moesif` namespace
+#[derive(Default, Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub struct RoutePolicy {
+    pub skip: Option<bool>,
+    pub log_body: Option<bool>,
+    pub max_body_size: Option<usize>,
+    pub user_id_header: Option<String>,
+    pub company_id_header: Option<String>,
+    pub application_id: Option<String>,
+}
+
+impl RoutePolicy {
+    // Read the policy from the route metadata attribute and from the dynamic metadata context.
+    // Dynamic metadata is set per request and wins over the route's static metadata.
+    pub fn from_request(config: &Config, msg: &ProcessingRequest) -> Self {
+        let namespace = &config.env.route_policy_namespace;
+        let mut policy = RoutePolicy::default();
+
+        if let Some(route_metadata) = find_attribute(&msg.attributes, "xds.route_metadata") {
+            let route_metadata = value_to_json(route_metadata);
+            // Envoy renders the Metadata message with its JSON field names
+            let fields = route_metadata
+                .get("filter_metadata")
+                .or_else(|| route_metadata.get("filterMetadata"))
+                .and_then(|filter_metadata| filter_metadata.get(namespace));
+            if let Some(fields) = fields {
+                policy.merge(Self::parse(fields.clone()));
+            }
+        }
+
+        if let Some(fields) = msg
+            .metadata_context
+            .as_ref()
+            .and_then(|metadata| metadata.filter_metadata.get(namespace))
+        {
+            policy.merge(Self::parse(struct_to_json(fields)));
+        }
+
+        if policy != RoutePolicy::default() {
+            log::trace!("Route policy: {:?}", policy);
+        }
+        policy
+    }
+
+    fn parse(fields: serde_json::Value) -> Self {
+        serde_json::from_value(fields)
+            .map_err(|e| log::warn!("Ignoring invalid route policy: {}", e))
+            .unwrap_or_default()
+    }
+
+    fn merge(&mut self, other: RoutePolicy) {
+        self.skip = other.skip.or(self.skip);
+        self.log_body = other.log_body.or(self.log_body);
+        self.max_body_size = other.max_body_size.or(self.max_body_size);
+        self.user_id_header = other.user_id_header.or(self.user_id_header.take());
+        self.company_id_header = other.company_id_header.or(self.company_id_header.take());
+        self.application_id = other.application_id.or(self.application_id.take());
+    }
+
+    pub fn skip(&self) -> bool {
+        self.skip.unwrap_or(false)
+    }
+
+    // The part of the policy that changes the configuration
+    fn overrides(&self) -> RoutePolicy {
+        RoutePolicy {
+            skip: None,
+            // Only decides where events are sent, see resolve_application_id
+            application_id: None,
+            ..self.clone()
+        }
+    }
+
+    // Configuration for the exchange, the global one unless the route overrides part of it
+    pub fn apply(&self, config: &Arc<Config>) -> Arc<Config> {
+        if self.overrides() == RoutePolicy::default() {
+            return Arc::clone(config);
+        }
+
+        let mut route_config = Config::clone(config);
+        if let Some(log_body) = self.log_body {
+            // The route opted in or out itself, the global path rules don't apply
+            route_config.env.log_body = log_body;
+            route_config.body_capture_paths.clear();
+        }
+        if let Some(max_body_size) = self.max_body_size {
+            route_config.env.max_body_size = max_body_size;
+        }
+        if let Some(user_id_header) = &self.user_id_header {
+            route_config.env.user_id_header = Some(user_id_header.clone());
+        }
+        if let Some(company_id_header) = &self.company_id_header {
+            route_config.env.company_id_header = Some(company_id_header.clone());
+        }
+
+        Arc::new(route_config)
+    }
+}
+
+const MAX_ROUTE_CONFIGS: usize = 256;
+
+// Route configurations are built once per distinct set of overrides instead of copying the
+// global configuration, JWKS and descriptor pool included, on every request
+#[derive(Default)]
+pub struct RouteConfigCache {
+    configs: Mutex<HashMap<RoutePolicy, Arc<Config>>>,
+}
+
+impl RouteConfigCache {
+    pub fn config_for(&self, policy: &RoutePolicy, config: &Arc<Config>) -> Arc<Config> {
+        let overrides = policy.overrides();
+        if overrides == RoutePolicy::default() {
+            return Arc::clone(config);
+        }
+
+        let mut configs = self.configs.lock().unwrap();
+        if let Some(route_config) = configs.get(&overrides) {
+            return Arc::clone(route_config);
+        }
+
+        let route_config = overrides.apply(config);
+        // Overrides from dynamic metadata could vary per request, don't grow without bound
+        if configs.len() < MAX_ROUTE_CONFIGS {
+            configs.insert(overrides, Arc::clone(&route_config));
+        }
+        route_config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EnvConfig;
+    use envoy_ext_proc_proto::envoy::config::core::v3::Metadata;
+    use prost_types::value::Kind;
+    use prost_types::{Struct, Value};
+    use std::collections::{BTreeMap, HashMap};
+
+    fn struct_value(fields: Vec<(&str, Kind)>) -> Struct {
+        Struct {
+            fields: fields
+                .into_iter()
+                .map(|(k, kind)| (k.to_string(), Value { kind: Some(kind) }))
+                .collect::<BTreeMap<_, _>>(),
+        }
+    }
+
+    #[test]
+    fn test_route_policy_overrides_global_config() {
+        // arrange
+        let route_metadata = struct_value(vec![(
+            "filter_metadata",
+            Kind::StructValue(struct_value(vec![(
+                "moesif",
+                Kind::StructValue(struct_value(vec![
+                    ("log_body", Kind::BoolValue(true)),
+                    (
+                        "user_id_header",
+                        Kind::StringValue("x-partner-id".to_string()),
+                    ),
+                ])),
+            )])),
+        )]);
+        let msg = ProcessingRequest {
+            attributes: HashMap::from([(
+                "envoy.filters.http.ext_proc".to_string(),
+                struct_value(vec![(
+                    "xds.route_metadata",
+                    Kind::StructValue(route_metadata),
+                )]),
+            )]),
+            metadata_context: Some(Metadata {
+                filter_metadata: HashMap::from([(
+                    "moesif".to_string(),
+                    struct_value(vec![("max_body_size", Kind::NumberValue(2048.0))]),
+                )]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let cfg = Arc::new(Config::new(EnvConfig {
+            route_policy_namespace: "moesif".to_string(),
+            user_id_header: Some("x-user-id".to_string()),
+            body_capture_paths: Some("^/orders".to_string()),
+            ..Default::default()
+        }));
+
+        // act
+        let policy = RoutePolicy::from_request(&cfg, &msg);
+        let route_config = policy.apply(&cfg);
+
+        // assert
+        assert!(!policy.skip());
+        assert!(route_config.env.log_body);
+        assert!(route_config.body_capture_paths.is_empty());
+        assert_eq!(route_config.env.max_body_size, 2048);
+        assert_eq!(
+            route_config.env.user_id_header,
+            Some("x-partner-id".to_string())
+        );
+        assert!(Arc::ptr_eq(&RoutePolicy::default().apply(&cfg), &cfg));
+    }
+
+    #[test]
+    fn test_route_config_cache_reuses_configs() {
+        // arrange
+        let cache = RouteConfigCache::default();
+        let cfg = Arc::new(Config::default());
+        let policy = RoutePolicy {
+            log_body: Some(true),
+            ..Default::default()
+        };
+        let skipped = RoutePolicy {
+            skip: Some(true),
+            log_body: Some(true),
+            ..Default::default()
+        };
+
+        // act
+        let first = cache.config_for(&policy, &cfg);
+        let second = cache.config_for(&skipped, &cfg);
+        let global = cache.config_for(&RoutePolicy::default(), &cfg);
+
+        // assert
+        assert!(first.env.log_body);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&global, &cfg));
+    }
+}
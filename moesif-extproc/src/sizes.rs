@@ -0,0 +1,161 @@
+use envoy_ext_proc_proto::envoy::service::ext_proc::v3::HttpHeaders;
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::event::Event;
+use crate::utils::header_value;
+
+// Header and body byte sizes of one direction of an exchange, known whether or not the body
+// itself is captured
+#[derive(Default, Debug, Clone)]
+pub struct MessageSize {
+    pub header_bytes: Option<usize>,
+    streamed_body_bytes: usize,
+    body_streamed: bool,
+    headers_only: bool,
+}
+
+impl MessageSize {
+    // Headers as they would be written on an HTTP/1.1 connection, "name: value\r\n" each.
+    // Pseudo-headers such as ":path" or ":status" belong to the request or status line there.
+    pub fn on_headers(&mut self, headers_msg: &HttpHeaders) {
+        let header_bytes = headers_msg
+            .headers
+            .iter()
+            .flat_map(|header_map| header_map.headers.iter())
+            .filter(|header| !header.key.starts_with(':'))
+            .map(|header| header.key.len() + header_value(header).len() + 4)
+            .sum();
+        self.header_bytes = Some(header_bytes);
+        self.headers_only = headers_msg.end_of_stream;
+    }
+
+    pub fn on_body_chunk(&mut self, chunk: &[u8]) {
+        self.body_streamed = true;
+        self.streamed_body_bytes += chunk.len();
+    }
+
+    // Content-Length when the message has one, otherwise the bytes Envoy streamed to us
+    pub fn body_bytes(&self, headers: &HashMap<String, String>) -> Option<usize> {
+        if self.headers_only {
+            return Some(0);
+        }
+
+        headers
+            .get("content-length")
+            .and_then(|length| length.trim().parse::<usize>().ok())
+            .or(self.body_streamed.then_some(self.streamed_body_bytes))
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct ExchangeSizes {
+    pub request: MessageSize,
+    pub response: MessageSize,
+}
+
+impl ExchangeSizes {
+    pub fn apply_to_event(&self, event: &mut Event) {
+        let request_body_bytes = self.request.body_bytes(&event.request.headers);
+        // Responses to HEAD and 304 responses have no body, whatever their Content-Length says
+        let response_body_bytes = event.response.as_ref().and_then(|response| {
+            if event.request.verb.eq_ignore_ascii_case("HEAD") || response.status == 304 {
+                Some(0)
+            } else {
+                self.response.body_bytes(&response.headers)
+            }
+        });
+
+        for (key, size) in [
+            ("request_header_size", self.request.header_bytes),
+            ("request_body_size", request_body_bytes),
+            ("response_header_size", self.response.header_bytes),
+            ("response_body_size", response_body_bytes),
+        ] {
+            if let Some(size) = size {
+                event.set_metadata(key, Value::from(size));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::ResponseInfo;
+    use bytes::Bytes;
+    use envoy_ext_proc_proto::envoy::config::core::v3::{HeaderMap, HeaderValue};
+
+    fn headers(pairs: &[(&str, &str)], end_of_stream: bool) -> HttpHeaders {
+        HttpHeaders {
+            headers: Some(HeaderMap {
+                headers: pairs
+                    .iter()
+                    .map(|(key, value)| HeaderValue {
+                        key: key.to_string(),
+                        raw_value: Bytes::from(value.to_string()),
+                        ..Default::default()
+                    })
+                    .collect(),
+            }),
+            end_of_stream,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sizes_without_captured_bodies() {
+        // arrange
+        let mut sizes = ExchangeSizes::default();
+        let mut event = Event::default();
+        event
+            .request
+            .headers
+            .insert("content-length".to_string(), "42".to_string());
+        event.response = Some(ResponseInfo::default());
+
+        // act
+        sizes.request.on_headers(&headers(
+            &[(":method", "POST"), ("content-length", "42")],
+            false,
+        ));
+        sizes
+            .response
+            .on_headers(&headers(&[(":status", "200")], false));
+        sizes.response.on_body_chunk(b"hello ");
+        sizes.response.on_body_chunk(b"world");
+        sizes.apply_to_event(&mut event);
+
+        // assert
+        assert_eq!(event.metadata["request_header_size"], 20);
+        assert_eq!(event.metadata["request_body_size"], 42);
+        assert_eq!(event.metadata["response_header_size"], 0);
+        assert_eq!(event.metadata["response_body_size"], 11);
+    }
+
+    #[test]
+    fn test_sizes_of_bodiless_responses() {
+        // arrange
+        let mut head = Event::default();
+        head.request.verb = "HEAD".to_string();
+        let mut not_modified = Event::default();
+        not_modified.request.verb = "GET".to_string();
+        for event in [&mut head, &mut not_modified] {
+            let mut response = ResponseInfo::default();
+            response
+                .headers
+                .insert("content-length".to_string(), "512".to_string());
+            event.response = Some(response);
+        }
+        not_modified.response.as_mut().unwrap().status = 304;
+        let sizes = ExchangeSizes::default();
+
+        // act
+        sizes.apply_to_event(&mut head);
+        sizes.apply_to_event(&mut not_modified);
+
+        // assert
+        assert_eq!(head.metadata["response_body_size"], 0);
+        assert_eq!(not_modified.metadata["response_body_size"], 0);
+    }
+}
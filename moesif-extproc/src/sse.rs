@@ -0,0 +1,424 @@
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use crate::compression::{is_identity, StreamDecoder};
+
+// A single Server-Sent Event, see https://html.spec.whatwg.org/multipage/server-sent-events.html
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub id: Option<String>,
+    pub data: String,
+}
+
+// Incremental parser for a text/event-stream body, fed with body chunks as they arrive.
+// Chunks can end anywhere, incomplete lines are kept until the rest comes in. An event whose
+// buffered lines grow past the size limit is dropped, so a stream without line breaks can't
+// pin memory.
+#[derive(Default, Debug)]
+pub struct SseParser {
+    line: Vec<u8>,
+    // Bytes of the current line, including those not kept while discarding
+    line_len: usize,
+    // Lines end with \r\n, \n or \r, a \n right after a \r ends nothing
+    after_cr: bool,
+    event: Option<String>,
+    id: Option<String>,
+    data: Vec<String>,
+    data_size: usize,
+    // Set once the event in progress exceeded the size limit, until its end
+    discarding: bool,
+    // Set for compressed streams, chunks are decoded before they are parsed
+    decoder: Option<StreamDecoder>,
+    decode_failed: bool,
+}
+
+impl SseParser {
+    // A parser for a stream sent with this Content-Encoding, None when it can't be decoded
+    pub fn for_content_encoding(content_encoding: Option<&str>) -> Option<Self> {
+        let content_encoding = match content_encoding.filter(|encoding| !is_identity(encoding)) {
+            Some(content_encoding) => content_encoding,
+            None => return Some(SseParser::default()),
+        };
+
+        match StreamDecoder::new(content_encoding) {
+            Ok(decoder) => Some(SseParser {
+                decoder: Some(decoder),
+                ..Default::default()
+            }),
+            Err(e) => {
+                log::debug!("Can't decode event stream: {:?}", e);
+                None
+            }
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8], max_size: usize) -> Vec<SseEvent> {
+        if self.decode_failed {
+            return Vec::new();
+        }
+        match self
+            .decoder
+            .as_mut()
+            .map(|decoder| decoder.decode(chunk, max_size))
+        {
+            Some(Ok(decoded)) => self.parse(&decoded, max_size),
+            Some(Err(e)) => {
+                log::debug!("Failed to decode event stream, ignoring the rest: {:?}", e);
+                self.decode_failed = true;
+                Vec::new()
+            }
+            None => self.parse(chunk, max_size),
+        }
+    }
+
+    fn parse(&mut self, chunk: &[u8], max_size: usize) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+
+        for &byte in chunk {
+            let after_cr = std::mem::replace(&mut self.after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {}
+                b'\n' | b'\r' => {
+                    if let Some(event) = self.end_line() {
+                        events.push(event);
+                    }
+                }
+                _ => {
+                    self.line_len += 1;
+                    if !self.discarding {
+                        self.line.push(byte);
+                        if self.line.len() + self.data_size > max_size {
+                            self.discard(max_size);
+                        }
+                    }
+                }
+            }
+        }
+
+        events
+    }
+
+    // A stream that ends without a trailing blank line still completes its last event. The
+    // decoder may still hold the end of a compressed stream.
+    pub fn finish(&mut self, max_size: usize) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        if let Some(decoder) = self.decoder.as_mut().filter(|_| !self.decode_failed) {
+            match decoder.finish() {
+                Ok(decoded) => events = self.parse(&decoded, max_size),
+                Err(e) => log::debug!("Failed to decode the end of the event stream: {:?}", e),
+            }
+        }
+        events.extend(self.finish_event());
+        events
+    }
+
+    fn finish_event(&mut self) -> Option<SseEvent> {
+        if self.discarding {
+            self.reset();
+            return None;
+        }
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.process_line(&String::from_utf8_lossy(&line));
+        }
+        self.dispatch()
+    }
+
+    fn end_line(&mut self) -> Option<SseEvent> {
+        let line_len = std::mem::take(&mut self.line_len);
+        let line = std::mem::take(&mut self.line);
+        if self.discarding {
+            // The blank line ending the dropped event
+            if line_len == 0 {
+                self.reset();
+            }
+            return None;
+        }
+        self.process_line(&String::from_utf8_lossy(&line))
+    }
+
+    fn discard(&mut self, max_size: usize) {
+        log::debug!("Server-Sent Event exceeds {} bytes, dropping it", max_size);
+        self.reset();
+        self.discarding = true;
+    }
+
+    fn reset(&mut self) {
+        self.line = Vec::new();
+        self.event = None;
+        self.id = None;
+        self.data = Vec::new();
+        self.data_size = 0;
+        self.discarding = false;
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            // Comment, often used as a keep-alive
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "id" => self.id = Some(value.to_string()),
+            "data" => {
+                self.data_size += value.len() + 1;
+                self.data.push(value.to_string());
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        let id = self.id.take();
+        self.data_size = 0;
+        if self.data.is_empty() {
+            return None;
+        }
+
+        Some(SseEvent {
+            event,
+            id,
+            data: std::mem::take(&mut self.data).join("\n"),
+        })
+    }
+}
+
+impl SseEvent {
+    // JSON data, as most APIs send, is kept as JSON
+    pub fn to_json(&self) -> Value {
+        let data = serde_json::from_str::<Value>(&self.data)
+            .unwrap_or_else(|_| Value::String(self.data.clone()));
+        let mut event = json!({ "data": data });
+        if let Some(name) = &self.event {
+            event["event"] = Value::String(name.clone());
+        }
+        if let Some(id) = &self.id {
+            event["id"] = Value::String(id.clone());
+        }
+        event
+    }
+}
+
+// The events of a captured text/event-stream body. Streams can run for a long time, so only
+// the first events, up to the size limit, and the last one are kept while all are counted.
+#[derive(Debug)]
+pub struct SseCapture {
+    parser: SseParser,
+    max_events: usize,
+    head: Vec<SseEvent>,
+    head_size: usize,
+    last: Option<SseEvent>,
+    count: usize,
+    started_at: DateTime<Utc>,
+    last_event_at: Option<DateTime<Utc>>,
+}
+
+impl SseCapture {
+    pub fn new(max_events: usize, parser: SseParser) -> Self {
+        SseCapture {
+            parser,
+            max_events,
+            head: Vec::new(),
+            head_size: 0,
+            last: None,
+            count: 0,
+            started_at: Utc::now(),
+            last_event_at: None,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8], max_size: usize) {
+        for event in self.parser.push(chunk, max_size) {
+            self.record(event, max_size);
+        }
+    }
+
+    pub fn finish(&mut self, max_size: usize) {
+        for event in self.parser.finish(max_size) {
+            self.record(event, max_size);
+        }
+    }
+
+    fn record(&mut self, event: SseEvent, max_size: usize) {
+        self.count += 1;
+        self.last_event_at = Some(Utc::now());
+
+        let size = event.data.len();
+        if self.last.is_none()
+            && self.head.len() < self.max_events
+            && self.head_size + size <= max_size
+        {
+            self.head_size += size;
+            self.head.push(event);
+        } else if size <= max_size {
+            self.last = Some(event);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn duration_ms(&self) -> Option<i64> {
+        Some((self.last_event_at? - self.started_at).num_milliseconds())
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::Array(
+            self.head
+                .iter()
+                .chain(self.last.iter())
+                .map(SseEvent::to_json)
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sse_parser_handles_split_chunks() {
+        // arrange
+        let mut parser = SseParser::default();
+
+        // act
+        let mut events = parser.push(
+            b": keep-alive\r\nevent: message_start\r\ndata: {\"a\"",
+            1000,
+        );
+        events.extend(parser.push(b":1}\r\n\r\ndata: line 1\ndata: line 2\n\n", 1000));
+        events.extend(parser.push(b"data: cr\rdata: only\r\r", 1000));
+        events.extend(parser.push(b"data: [DONE]", 1000));
+        let last = parser.finish(1000);
+
+        // assert
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("message_start".to_string()),
+                    id: None,
+                    data: "{\"a\":1}".to_string(),
+                },
+                SseEvent {
+                    event: None,
+                    id: None,
+                    data: "line 1\nline 2".to_string(),
+                },
+                SseEvent {
+                    event: None,
+                    id: None,
+                    data: "cr\nonly".to_string(),
+                },
+            ]
+        );
+        assert_eq!(last[0].data, "[DONE]");
+    }
+
+    #[test]
+    fn test_sse_parser_drops_oversized_events() {
+        // arrange
+        let mut parser = SseParser::default();
+        let many_lines = "data: 0123456789\n".repeat(10);
+
+        // act
+        let mut events = parser.push(&[b'x'; 100], 64);
+        events.extend(parser.push(b"\n\ndata: next\n\n", 64));
+        events.extend(parser.push(many_lines.as_bytes(), 64));
+        events.extend(parser.push(b"\ndata: after\n\n", 64));
+
+        // assert
+        assert!(parser.line.is_empty() && parser.data.is_empty());
+        assert_eq!(
+            events.iter().map(|e| e.data.as_str()).collect::<Vec<_>>(),
+            vec!["next", "after"]
+        );
+    }
+
+    #[test]
+    fn test_sse_capture_keeps_first_and_last_events() {
+        // arrange
+        let mut capture = SseCapture::new(2, SseParser::default());
+
+        // act
+        for i in 0..5 {
+            capture.push(
+                format!("id: {}\ndata: {{\"n\":{}}}\n\n", i, i).as_bytes(),
+                1000,
+            );
+        }
+        capture.push(b"event: done\ndata: bye", 1000);
+        capture.finish(1000);
+
+        // assert
+        assert_eq!(capture.count(), 6);
+        assert_eq!(
+            capture.to_json(),
+            json!([
+                {"id": "0", "data": {"n": 0}},
+                {"id": "1", "data": {"n": 1}},
+                {"event": "done", "data": "bye"},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_sse_capture_decodes_gzip_streams() {
+        // arrange
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(b"data: {\"n\":1}\n\nevent: done\ndata: bye\n\n")
+            .unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let parser = SseParser::for_content_encoding(Some("gzip")).unwrap();
+        let mut capture = SseCapture::new(10, parser);
+
+        // act
+        for chunk in gzipped.chunks(5) {
+            capture.push(chunk, 1000);
+        }
+        capture.finish(1000);
+
+        // assert
+        assert_eq!(capture.count(), 2);
+        assert_eq!(
+            capture.to_json(),
+            json!([{"data": {"n": 1}}, {"event": "done", "data": "bye"}])
+        );
+        assert!(SseParser::for_content_encoding(Some("zstd")).is_none());
+    }
+
+    #[test]
+    fn test_sse_capture_is_bounded_without_newlines() {
+        // arrange
+        let mut capture = SseCapture::new(2, SseParser::default());
+
+        // act
+        capture.push(b"data: ", 256);
+        for _ in 0..10 {
+            capture.push(&[b'a'; 100], 256);
+        }
+        let buffered = capture.parser.line.len();
+        capture.finish(256);
+
+        // assert
+        assert!(buffered <= 256);
+        assert_eq!(capture.count(), 0);
+        assert_eq!(capture.to_json(), json!([]));
+    }
+}
@@ -0,0 +1,61 @@
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+// Points in time observed for a single HTTP exchange on an ext_proc stream
+#[derive(Default, Debug, Clone)]
+pub struct ExchangeTimings {
+    pub request_headers: Option<DateTime<Utc>>,
+    pub request_end: Option<DateTime<Utc>>,
+    pub response_headers: Option<DateTime<Utc>>,
+    pub response_end: Option<DateTime<Utc>>,
+}
+
+impl ExchangeTimings {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "request_headers_at": self.request_headers.map(|t| t.to_rfc3339()),
+            "request_end_at": self.request_end.map(|t| t.to_rfc3339()),
+            "response_headers_at": self.response_headers.map(|t| t.to_rfc3339()),
+            "response_end_at": self.response_end.map(|t| t.to_rfc3339()),
+            "request_duration_ms": duration_ms(self.request_headers, self.request_end),
+            // Time spent waiting on the upstream once the request was fully sent
+            "upstream_duration_ms": duration_ms(
+                self.request_end.or(self.request_headers),
+                self.response_headers
+            ),
+            "response_duration_ms": duration_ms(self.response_headers, self.response_end),
+            "total_duration_ms": duration_ms(self.request_headers, self.response_end),
+        })
+    }
+}
+
+fn duration_ms(start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> Option<i64> {
+    Some((end? - start?).num_milliseconds())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_timings_to_json() {
+        // arrange
+        let start = Utc::now();
+        let timings = ExchangeTimings {
+            request_headers: Some(start),
+            request_end: Some(start + Duration::milliseconds(5)),
+            response_headers: Some(start + Duration::milliseconds(45)),
+            response_end: None,
+        };
+
+        // act
+        let json = timings.to_json();
+
+        // assert
+        assert_eq!(json["request_duration_ms"], 5);
+        assert_eq!(json["upstream_duration_ms"], 40);
+        assert_eq!(json["response_duration_ms"], serde_json::Value::Null);
+        assert_eq!(json["total_duration_ms"], serde_json::Value::Null);
+    }
+}
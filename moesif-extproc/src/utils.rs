@@ -1,20 +1,29 @@
 use envoy_ext_proc_proto::envoy::service::ext_proc::v3::{
-    processing_response, HeadersResponse, HttpHeaders, ProcessingResponse,
+    processing_request, processing_response, BodyResponse, HeadersResponse, HttpHeaders,
+    HttpTrailers, ProcessingResponse, TrailersResponse,
 };
 use tonic::Status;
 
-use envoy_ext_proc_proto::envoy::config::core::v3::HeaderMap;
+use envoy_ext_proc_proto::envoy::config::core::v3::{HeaderMap, HeaderValue};
+use prost_types::Struct;
 
 use crate::config::Config;
+use crate::grpc_payload::grpc_status_to_http;
+use crate::id_source::resolve_first;
+use crate::jwt;
+use crate::metadata::{find_attribute, value_as_string, value_to_json};
 use crate::root_context::EventRootContext;
+use crate::timing::ExchangeTimings;
 use reqwest::header::HeaderMap as ReqwestHeaderMap;
 
-use crate::event::{Event, ResponseInfo};
+use crate::event::{Event, ResponseHeaders, ResponseInfo, SET_COOKIE_HEADER};
 use bytes::Bytes;
 use chrono::Utc;
 use log::LevelFilter;
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -61,7 +70,7 @@ pub async fn process_request_headers(
     event.request.headers.retain(|k, _| !k.starts_with(":"));
     log::trace!("Filtered headers: {:?}", event.request.headers);
 
-    event.request.ip_address = get_client_ip(&event.request.headers);
+    event.request.ip_address = resolve_client_ip(config, &event.request.headers, None);
     log::trace!("Client IP: {:?}", event.request.ip_address);
 
     event.request.api_version = event.request.headers.get("x-api-version").cloned();
@@ -72,6 +81,9 @@ pub async fn process_request_headers(
 
     add_user_and_company_id_headers_to_event(config, event).await;
 
+    add_session_token_to_event(config, event);
+    log::trace!("Session token present: {}", event.session_token.is_some());
+
     log_event(event);
 }
 
@@ -84,11 +96,7 @@ pub async fn process_response_headers(event: &mut Event, response_headers_msg: &
         log::trace!("List of Headers in HTTP Response:");
 
         for header in &header_map.headers {
-            log::trace!(
-                "{} - {}",
-                header.key,
-                String::from_utf8_lossy(&header.raw_value)
-            );
+            log::trace!("{} - {}", header.key, header_value(header));
         }
     } else {
         log::warn!("No headers found in response.");
@@ -99,21 +107,113 @@ pub async fn process_response_headers(event: &mut Event, response_headers_msg: &
     let response = ResponseInfo {
         time: Utc::now().to_rfc3339(),
         status: status_str.parse::<usize>().unwrap_or(0),
-        headers: header_list_to_map(response_headers_msg.headers.clone()),
+        headers: ResponseHeaders {
+            values: header_list_to_map(response_headers_msg.headers.clone()),
+            set_cookies: set_cookie_values(response_headers_msg.headers.as_ref()),
+        },
+        transfer_encoding: None,
         ip_address: None,
         body: serde_json::Value::Null,
     };
 
+    let headers = response.headers.clone();
     event.response = Some(response);
+
+    // Trailers-only gRPC responses carry the status in the headers
+    record_grpc_status(event, &headers);
 }
 
-pub async fn store_and_flush_event(event_context: &Arc<Mutex<EventRootContext>>, event: &Event) {
+// Handle request trailers
+pub fn process_request_trailers(event: &mut Event, trailers_msg: &HttpTrailers) {
+    log::trace!("Processing request trailers: {:?}", trailers_msg);
+
+    let trailers = header_list_to_map(trailers_msg.trailers.clone());
+    event.set_metadata("request_trailers", serde_json::json!(trailers));
+}
+
+// Handle response trailers
+pub fn process_response_trailers(event: &mut Event, trailers_msg: &HttpTrailers) {
+    log::trace!("Processing response trailers: {:?}", trailers_msg);
+
+    let trailers = header_list_to_map(trailers_msg.trailers.clone());
+    record_grpc_status(event, &trailers);
+    event.set_metadata("response_trailers", serde_json::json!(trailers));
+}
+
+fn record_grpc_status(event: &mut Event, headers: &HashMap<String, String>) {
+    if let Some(status) = headers
+        .get("grpc-status")
+        .and_then(|s| s.trim().parse::<u32>().ok())
+    {
+        log::trace!("gRPC status: {}", status);
+        event.set_metadata("grpc_status", serde_json::json!(status));
+        if let Some(response) = event.response.as_mut() {
+            response.status = grpc_status_to_http(status);
+        }
+    }
+
+    // grpc-message is percent-encoded on the wire
+    if let Some(message) = headers.get("grpc-message") {
+        let message = percent_decode_str(message).decode_utf8_lossy().to_string();
+        event.set_metadata("grpc_message", serde_json::Value::String(message));
+    }
+}
+
+// Attribute name and the event metadata key it is recorded under
+const METADATA_ATTRIBUTES: [(&str, &str); 5] = [
+    ("request.protocol", "protocol"),
+    ("xds.route_name", "route_name"),
+    ("xds.cluster_name", "cluster_name"),
+    ("upstream.address", "upstream_host"),
+    ("connection.mtls", "mtls"),
+];
+
+// Handle the attributes Envoy sends when `requestAttributes` / `responseAttributes` are configured
+pub fn process_attributes(
+    config: &Config,
+    event: &mut Event,
+    attributes: &HashMap<String, Struct>,
+) {
+    if attributes.is_empty() {
+        return;
+    }
+    log::trace!("Received attributes: {:?}", attributes);
+
+    // The downstream peer address can't be forged by the client, prefer it over headers
+    if let Some(source_address) =
+        find_attribute(attributes, "source.address").and_then(value_as_string)
+    {
+        event.request.ip_address =
+            resolve_client_ip(config, &event.request.headers, Some(&source_address));
+        log::trace!("Client IP from attributes: {:?}", event.request.ip_address);
+    }
+
+    for (attribute, key) in METADATA_ATTRIBUTES {
+        if let Some(value) = find_attribute(attributes, attribute) {
+            event.set_metadata(key, value_to_json(value));
+        }
+    }
+}
+
+// Stamp the response time with the end of the response rather than the arrival of its headers
+pub fn finalize_event_timings(event: &mut Event, timings: &ExchangeTimings) {
+    if let (Some(response), Some(response_end)) = (event.response.as_mut(), timings.response_end) {
+        response.time = response_end.to_rfc3339();
+    }
+    event.set_metadata("timing", timings.to_json());
+}
+
+pub async fn store_and_flush_event(
+    event_context: &Arc<Mutex<EventRootContext>>,
+    application_id: &str,
+    event: &Event,
+) {
     log_event(event);
 
     let mut event_root_context = event_context.lock().await;
 
-    // Add the event to the main buffer
-    event_root_context.push_event(event).await;
+    // Add the event to the queue of its application
+    event_root_context.push_event(application_id, event).await;
 
     // Check if we need to flush the buffer
     event_root_context.check_and_flush_buffer().await;
@@ -146,27 +246,108 @@ pub async fn add_user_and_company_id_headers_to_event(config: &Arc<Config>, even
         std::env::var("COMPANY_ID_HEADER")
     );
 
+    // JWT claims take precedence, the plain headers are used as a fallback
+    let user_id_field = config.env.authorization_user_id_field.as_ref();
+    let company_id_field = config.env.authorization_company_id_field.as_ref();
+    if user_id_field.is_some() || company_id_field.is_some() {
+        if let Some(claims) = jwt::extract_claims(config, &event.request.headers) {
+            event.user_id = user_id_field.and_then(|field| jwt::claim_as_string(&claims, field));
+            event.company_id =
+                company_id_field.and_then(|field| jwt::claim_as_string(&claims, field));
+            log::trace!(
+                "Identified from JWT claims: user_id={:?} company_id={:?}",
+                event.user_id,
+                event.company_id
+            );
+        }
+    }
+
+    // Then the configured sources in order, the first one that yields a value wins
+    if event.user_id.is_none() {
+        event.user_id = resolve_first(
+            &config.user_id_sources,
+            &event.request.headers,
+            &event.request.uri,
+        );
+    }
+
+    if event.company_id.is_none() {
+        event.company_id = resolve_first(
+            &config.company_id_sources,
+            &event.request.headers,
+            &event.request.uri,
+        );
+    }
+
     if let Some(user_id_header) = &config.env.user_id_header {
-        let lowered_user_id_header = user_id_header.to_lowercase();
-        event.user_id = event.request.headers.get(&lowered_user_id_header).cloned();
+        if event.user_id.is_none() {
+            let lowered_user_id_header = user_id_header.to_lowercase();
+            event.user_id = event.request.headers.get(&lowered_user_id_header).cloned();
+        }
     }
 
     if let Some(company_id_header) = &config.env.company_id_header {
-        let lowered_comp_id_header = company_id_header.to_lowercase();
-        event.company_id = event.request.headers.get(&lowered_comp_id_header).cloned();
+        if event.company_id.is_none() {
+            let lowered_comp_id_header = company_id_header.to_lowercase();
+            event.company_id = event.request.headers.get(&lowered_comp_id_header).cloned();
+        }
     }
 }
 
+pub fn add_session_token_to_event(config: &Config, event: &mut Event) {
+    let from_header = config
+        .env
+        .session_token_header
+        .as_ref()
+        .and_then(|header| event.request.headers.get(&header.to_lowercase()).cloned())
+        .filter(|token| !token.is_empty());
+
+    let token = from_header.or_else(|| {
+        config
+            .env
+            .session_token_cookie
+            .as_ref()
+            .and_then(|cookie| get_cookie_value(&event.request.headers, cookie))
+    });
+
+    event.session_token = token.map(|token| session_token_value(config, &token));
+}
+
+// Session tokens are often credentials, optionally only a SHA-256 digest is sent to Moesif
+pub fn session_token_value(config: &Config, token: &str) -> String {
+    if config.env.hash_session_token {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    } else {
+        token.to_string()
+    }
+}
+
+// The pseudo headers are removed from the event, read the authority from the message itself
+pub fn request_authority(headers_msg: &HttpHeaders) -> Option<String> {
+    let headers = header_list_to_map(headers_msg.headers.clone());
+    headers
+        .get(":authority")
+        .or_else(|| headers.get("host"))
+        .cloned()
+}
+
+pub fn get_cookie_value(headers: &HashMap<String, String>, name: &str) -> Option<String> {
+    headers.get("cookie")?.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        if key.trim() == name && !value.is_empty() {
+            Some(value.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
 pub fn extract_status(headers_msg: &HttpHeaders) -> String {
     if let Some(header_map) = &headers_msg.headers {
         log::trace!("List of Significant Headers in HTTP Response:");
 
         for header in &header_map.headers {
-            log::trace!(
-                "{} - {}",
-                header.key,
-                String::from_utf8_lossy(&header.raw_value)
-            );
+            log::trace!("{} - {}", header.key, header_value(header));
         }
     }
 
@@ -178,7 +359,7 @@ pub fn extract_status(headers_msg: &HttpHeaders) -> String {
                 .headers
                 .iter()
                 .find(|header| header.key == ":status")
-                .map(|header| String::from_utf8_lossy(&header.raw_value).to_string())
+                .map(header_value)
         })
         .unwrap_or_else(|| "0".to_string());
 
@@ -187,20 +368,160 @@ pub fn extract_status(headers_msg: &HttpHeaders) -> String {
     status_str
 }
 
+// Depending on its version, Envoy sets either the raw bytes or the string value
+pub fn header_value(header: &HeaderValue) -> String {
+    if header.raw_value.is_empty() {
+        header.value.clone()
+    } else {
+        String::from_utf8_lossy(&header.raw_value).to_string()
+    }
+}
+
+// Repeated headers are combined into one value as described in RFC 9110, except for
+// set-cookie whose values can't be combined, the map keeps the first one and all of them are
+// read with set_cookie_values
 pub fn header_list_to_map(header_map: Option<HeaderMap>) -> HashMap<String, String> {
-    let mut map = HashMap::new();
+    let mut map: HashMap<String, String> = HashMap::new();
 
     if let Some(header_map) = header_map {
         for header in header_map.headers {
             let key = header.key.to_lowercase();
-            let value = String::from_utf8_lossy(&header.raw_value).to_string(); // Convert raw_value to String
-            map.insert(key, value);
+            let value = header_value(&header);
+            match map.get_mut(&key) {
+                Some(_) if key == SET_COOKIE_HEADER => {}
+                Some(existing) => {
+                    // Split cookie headers of HTTP/2 are joined back with "; "
+                    let separator = if key == "cookie" { "; " } else { ", " };
+                    existing.push_str(separator);
+                    existing.push_str(&value);
+                }
+                None => {
+                    map.insert(key, value);
+                }
+            }
         }
     }
 
     map
 }
 
+pub fn set_cookie_values(header_map: Option<&HeaderMap>) -> Vec<String> {
+    header_map
+        .map(|header_map| {
+            header_map
+                .headers
+                .iter()
+                .filter(|header| header.key.eq_ignore_ascii_case(SET_COOKIE_HEADER))
+                .map(header_value)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn resolve_client_ip(
+    config: &Config,
+    headers: &HashMap<String, String>,
+    source_address: Option<&str>,
+) -> Option<String> {
+    let source_ip = source_address.and_then(parse_ip);
+    let forwarded = forwarded_chain(headers);
+
+    // Walk the proxy chain from the right, the first hop that isn't a trusted proxy is the client.
+    // Entries left of it may have been sent by the client itself and can't be trusted.
+    if !config.env.trusted_proxies.is_empty() {
+        let chain: Vec<Option<IpAddr>> = forwarded
+            .iter()
+            .copied()
+            .chain(source_ip.map(Some))
+            .collect();
+        for hop in chain.iter().rev() {
+            match hop {
+                Some(ip) if is_trusted_proxy(config, ip) => continue,
+                Some(ip) => return Some(ip.to_string()),
+                // An unknown or obfuscated hop can't be trusted, nor anything left of it
+                None => return source_ip.map(|ip| ip.to_string()),
+            }
+        }
+        if let Some(Some(ip)) = chain.first() {
+            return Some(ip.to_string());
+        }
+    }
+
+    // Same as Envoy's xff_num_trusted_hops: with N trusted proxies in front of Envoy, the client
+    // is the (N+1)th address from the right of X-Forwarded-For, which ends with the address
+    // Envoy appended
+    let trusted_hops = config.env.xff_trusted_hops;
+    if trusted_hops > 0 && forwarded.len() > trusted_hops {
+        return forwarded[forwarded.len() - 1 - trusted_hops]
+            .or(source_ip)
+            .map(|ip| ip.to_string());
+    }
+
+    match source_ip {
+        Some(ip) => Some(ip.to_string()),
+        None => get_client_ip(headers),
+    }
+}
+
+fn is_trusted_proxy(config: &Config, ip: &IpAddr) -> bool {
+    config
+        .env
+        .trusted_proxies
+        .iter()
+        .any(|network| network.contains(ip))
+}
+
+// Addresses added by proxies, oldest first. Hops that aren't addresses, such as RFC 7239
+// `unknown` or obfuscated identifiers, are kept as None so they still count as hops.
+fn forwarded_chain(headers: &HashMap<String, String>) -> Vec<Option<IpAddr>> {
+    headers
+        .get("x-forwarded-for")
+        .or_else(|| headers.get("forwarded"))
+        .map(|value| {
+            forwarded_addresses(value)
+                .into_iter()
+                .map(parse_ip)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Split a forwarding header into its addresses. Handles plain lists as well as the
+// RFC 7239 syntax, e.g. `for=192.0.2.60;proto=http, for="[2001:db8:cafe::17]:4711"`
+fn forwarded_addresses(value: &str) -> Vec<&str> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            let element = element.trim();
+            if !element.contains('=') {
+                return Some(element);
+            }
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    Some(value.trim().trim_matches('"'))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+// Accepts a bare address or one with a port, e.g. "10.0.0.1:5000", "[::1]:5000" or "[::1]"
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    IpAddr::from_str(value)
+        .ok()
+        .or_else(|| SocketAddr::from_str(value).ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            value
+                .strip_prefix('[')
+                .and_then(|v| v.strip_suffix(']'))
+                .and_then(|v| IpAddr::from_str(v).ok())
+        })
+}
+
 pub fn get_client_ip(headers: &HashMap<String, String>) -> Option<String> {
     let possible_headers = vec![
         "x-client-ip",
@@ -219,10 +540,9 @@ pub fn get_client_ip(headers: &HashMap<String, String>) -> Option<String> {
 
     for header in possible_headers {
         if let Some(value) = headers.get(header) {
-            let ips: Vec<&str> = value.split(',').collect();
-            for ip in ips {
-                if IpAddr::from_str(ip.trim()).is_ok() {
-                    return Some(ip.trim().to_string());
+            for address in forwarded_addresses(value) {
+                if let Some(ip) = parse_ip(address) {
+                    return Some(ip.to_string());
                 }
             }
         }
@@ -239,16 +559,34 @@ pub fn serialize_event_to_bytes(event: &Event) -> Bytes {
     Bytes::from(serde_json::to_vec(event).unwrap())
 }
 
-pub fn simplified_grpc_response() -> ProcessingResponse {
-    let headers_response = HeadersResponse { response: None };
+// Answer each phase with its matching response type, Envoy treats a mismatch as a protocol error
+pub fn grpc_response_for(request: &processing_request::Request) -> ProcessingResponse {
+    let response = match request {
+        processing_request::Request::RequestHeaders(_) => {
+            processing_response::Response::RequestHeaders(HeadersResponse::default())
+        }
+        processing_request::Request::ResponseHeaders(_) => {
+            processing_response::Response::ResponseHeaders(HeadersResponse::default())
+        }
+        processing_request::Request::RequestBody(_) => {
+            processing_response::Response::RequestBody(BodyResponse::default())
+        }
+        processing_request::Request::ResponseBody(_) => {
+            processing_response::Response::ResponseBody(BodyResponse::default())
+        }
+        processing_request::Request::RequestTrailers(_) => {
+            processing_response::Response::RequestTrailers(TrailersResponse::default())
+        }
+        processing_request::Request::ResponseTrailers(_) => {
+            processing_response::Response::ResponseTrailers(TrailersResponse::default())
+        }
+    };
 
     ProcessingResponse {
         dynamic_metadata: None,
         mode_override: None,
         override_message_timeout: None,
-        response: Some(processing_response::Response::RequestHeaders(
-            headers_response,
-        )),
+        response: Some(response),
     }
 }
 
@@ -369,9 +707,7 @@ mod tests {
         };
 
         // create our app config composed of our env config
-        let cfg = Arc::new(Config{
-            env: env_config,
-        });
+        let cfg = Arc::new(Config::new(env_config));
 
         // create a default event object
         let mut event = Event::default();
@@ -383,4 +719,166 @@ mod tests {
         assert_eq!(event.user_id, Some(user_id_header_value.clone()));
         assert_eq!(event.company_id, Some(company_id_header_value.clone()))
     }
+
+    #[test]
+    fn test_resolve_client_ip_prefers_source_address() {
+        // arrange
+        let headers = HashMap::from([(
+            "x-forwarded-for".to_string(),
+            "1.1.1.1, 203.0.113.7, 10.0.0.2".to_string(),
+        )]);
+        let cfg = Config::new(EnvConfig::default());
+        let cfg_with_hops = Config::new(EnvConfig {
+            xff_trusted_hops: 1,
+            ..Default::default()
+        });
+
+        // act
+        let from_source = resolve_client_ip(&cfg, &headers, Some("10.0.0.2:41234"));
+        let from_xff = resolve_client_ip(&cfg_with_hops, &headers, Some("10.0.0.2:41234"));
+        let from_headers = resolve_client_ip(&cfg, &headers, None);
+
+        // assert
+        assert_eq!(from_source, Some("10.0.0.2".to_string()));
+        assert_eq!(from_xff, Some("203.0.113.7".to_string()));
+        assert_eq!(from_headers, Some("1.1.1.1".to_string()));
+    }
+
+    #[test]
+    fn test_resolve_client_ip_skips_trusted_proxies() {
+        // arrange
+        let headers = HashMap::from([(
+            "x-forwarded-for".to_string(),
+            "6.6.6.6, 198.51.100.4:3000, 10.1.2.3".to_string(),
+        )]);
+        let forwarded_headers = HashMap::from([(
+            "forwarded".to_string(),
+            r#"for=unknown, for="[2001:db8:cafe::17]:4711";proto=https"#.to_string(),
+        )]);
+        let cfg = Config::new(EnvConfig {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        });
+
+        // act
+        let client_ip = resolve_client_ip(&cfg, &headers, Some("10.0.0.5:8080"));
+        let forwarded_ip = get_client_ip(&forwarded_headers);
+
+        // assert
+        assert_eq!(client_ip, Some("198.51.100.4".to_string()));
+        assert_eq!(forwarded_ip, Some("2001:db8:cafe::17".to_string()));
+    }
+
+    #[test]
+    fn test_resolve_client_ip_stops_at_unknown_hop() {
+        // arrange
+        let headers = HashMap::from([(
+            "x-forwarded-for".to_string(),
+            "6.6.6.6, unknown, 10.1.2.3".to_string(),
+        )]);
+        let cfg = Config::new(EnvConfig {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        });
+
+        // act
+        let client_ip = resolve_client_ip(&cfg, &headers, Some("10.0.0.5:8080"));
+        let without_source = resolve_client_ip(&cfg, &headers, None);
+
+        // assert
+        assert_eq!(client_ip, Some("10.0.0.5".to_string()));
+        assert_eq!(without_source, None);
+    }
+
+    #[test]
+    fn test_process_response_trailers_records_grpc_status() {
+        // arrange
+        let trailers = HttpTrailers {
+            trailers: Some(HeaderMap {
+                headers: vec![
+                    HeaderValue {
+                        key: "grpc-status".to_string(),
+                        raw_value: Bytes::from("5"),
+                        ..Default::default()
+                    },
+                    HeaderValue {
+                        key: "grpc-message".to_string(),
+                        raw_value: Bytes::from("user%20not%20found"),
+                        ..Default::default()
+                    },
+                ],
+            }),
+        };
+        let mut event = Event::default();
+
+        // act
+        process_response_trailers(&mut event, &trailers);
+
+        // assert
+        assert_eq!(event.metadata["grpc_status"], 5);
+        assert_eq!(event.metadata["grpc_message"], "user not found");
+        assert_eq!(event.metadata["response_trailers"]["grpc-status"], "5");
+    }
+
+    #[test]
+    fn test_header_list_to_map_keeps_repeated_headers() {
+        // arrange
+        let header = |key: &str, raw_value: &str, value: &str| HeaderValue {
+            key: key.to_string(),
+            raw_value: Bytes::from(raw_value.to_string()),
+            value: value.to_string(),
+        };
+        let header_map = HeaderMap {
+            headers: vec![
+                header("Via", "1.1 edge", ""),
+                header("via", "", "1.1 envoy"),
+                header("set-cookie", "a=1; Path=/", ""),
+                header("set-cookie", "b=2; HttpOnly", ""),
+            ],
+        };
+
+        // act
+        let headers = header_list_to_map(Some(header_map.clone()));
+        let response = ResponseInfo {
+            headers: ResponseHeaders {
+                values: headers.clone(),
+                set_cookies: set_cookie_values(Some(&header_map)),
+            },
+            ..Default::default()
+        };
+        let serialized = serde_json::to_value(&response).unwrap();
+        let read_back: ResponseInfo = serde_json::from_value(serialized.clone()).unwrap();
+
+        // assert
+        assert_eq!(headers["via"], "1.1 edge, 1.1 envoy");
+        assert_eq!(headers["set-cookie"], "a=1; Path=/");
+        assert_eq!(
+            serialized["headers"]["set-cookie"],
+            serde_json::json!(["a=1; Path=/", "b=2; HttpOnly"])
+        );
+        assert_eq!(serialized["headers"]["via"], "1.1 edge, 1.1 envoy");
+        assert_eq!(read_back.headers, response.headers);
+    }
+
+    #[test]
+    fn test_add_session_token_to_event_from_cookie() {
+        // arrange
+        let cfg = Config::new(EnvConfig {
+            session_token_header: Some("X-Session-Token".to_string()),
+            session_token_cookie: Some("sid".to_string()),
+            hash_session_token: true,
+            ..Default::default()
+        });
+        let mut event = Event::default();
+        event.request.headers.insert("cookie".to_string(), "theme=dark; sid=abc".to_string());
+
+        // act
+        add_session_token_to_event(&cfg, &mut event);
+
+        // assert
+        assert_eq!(
+            event.session_token,
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string())
+        );
+    }
 }